@@ -1,7 +1,8 @@
 import * as crypto from 'crypto'
+import * as readline from 'readline';
 import { getPublicKey } from '@noble/secp256k1';
 import { utils } from 'ethereum-cryptography/secp256k1.js';
-import { ecdsaSign, ecdh } from 'ethereum-cryptography/secp256k1-compat.js';
+import { ecdsaSign, ecdsaRecover, ecdh } from 'ethereum-cryptography/secp256k1-compat.js';
 
 // Borrowed from @ethereumjs/devp2p
 const concatKDF = (keyMaterial, keyLength) => {
@@ -30,14 +31,18 @@ const ecdhX = (publicKey, privateKey) => {
 };
 
 // Borrowed from @ethereumjs/devp2p
-const taggedKdf = (remotePublicKey, data, sharedMacData = null) => {
-    const privateKey = utils.randomPrivateKey();
+const taggedKdf = (
+    remotePublicKey,
+    data,
+    sharedMacData = null,
+    privateKey = utils.randomPrivateKey(),
+    IV = crypto.randomBytes(16),
+) => {
     const publicKey = getPublicKey(privateKey, false);
     const key = concatKDF(ecdhX(remotePublicKey, privateKey), 32);
     const ekey = key.slice(0, 16); // encryption key
 
     // encrypt
-    const IV = crypto.randomBytes(16);
     const cipher = crypto.createCipheriv('aes-128-ctr', ekey, IV);
     const encryptedData = cipher.update(data);
     const dataIV = Buffer.concat([IV, encryptedData]);
@@ -52,41 +57,92 @@ const taggedKdf = (remotePublicKey, data, sharedMacData = null) => {
     return Buffer.concat([publicKey, dataIV, tag]);
 };
 
-const input = JSON.parse(process.argv[2]);
-switch (input.type) {
-    case 'Ecdhx': {
-        const privateKey = Buffer.from(input.privateKey, 'hex');
-        const publicKey = Buffer.from(input.publicKey, 'hex');
-        console.log(ecdhX(publicKey, privateKey).toString('hex'));
-    }
-        break;
+// Borrowed from @ethereumjs/devp2p
+const eciesDecrypt = (privateKey, data, sharedMacData = null) => {
+    const publicKey = data.slice(0, 65);
+    const dataIV = data.slice(65, -32);
+    const tag = data.slice(-32);
 
-    case 'EcdsaSign': {
-        const ephemeralPrivateKey = Buffer.from(input.ephemeralPrivateKey, 'hex');
-        const msg = Buffer.from(input.msg, 'hex');
+    // derive keys
+    const key = concatKDF(ecdhX(publicKey, privateKey), 32);
+    const ekey = key.slice(0, 16); // encryption key
+    const mkey = crypto.createHash('sha256').update(key.slice(16, 32)).digest(); // MAC key
 
-        const sig = ecdsaSign(msg, ephemeralPrivateKey);
-        console.log(Buffer.concat([Buffer.from(sig.signature), Buffer.from([sig.recid])]).toString('hex'));
+    // check the tag
+    const _tag = crypto
+        .createHmac('sha256', mkey)
+        .update(Buffer.concat([dataIV, sharedMacData]))
+        .digest();
+    if (tag.length !== _tag.length || !crypto.timingSafeEqual(tag, _tag)) {
+        throw new Error('Invalid ECIES tag');
     }
-        break;
 
-    case 'TaggedKdf': {
-        const msg = Buffer.from(input.msg, 'hex');
-        const sharedMacData = Buffer.from(input.macData, 'hex');
-        const rpk = Buffer.from(input.remotePublicKey, 'hex');
-        console.log(taggedKdf(rpk, msg, sharedMacData).toString('hex'));
-    }
-        break
+    // decrypt
+    const IV = dataIV.slice(0, 16);
+    const encryptedData = dataIV.slice(16);
+    const decipher = crypto.createDecipheriv('aes-128-ctr', ekey, IV);
+    return decipher.update(encryptedData);
+};
 
-    case 'ConcatKdf': {
-        const publicKey = Buffer.from(input.msg, 'hex').slice(2).slice(0, 65);
-        const privateKey = Buffer.from(input.privateKey, 'hex');
+const handle = (input) => {
+    switch (input.type) {
+        case 'Ecdhx': {
+            const privateKey = Buffer.from(input.privateKey, 'hex');
+            const publicKey = Buffer.from(input.publicKey, 'hex');
+            return ecdhX(publicKey, privateKey);
+        }
 
-        // derive keys
-        console.log(concatKDF(ecdhX(publicKey, privateKey), 32).slice(0, 16).toString('hex'));
+        case 'EcdsaSign': {
+            const ephemeralPrivateKey = Buffer.from(input.ephemeralPrivateKey, 'hex');
+            const msg = Buffer.from(input.msg, 'hex');
+
+            const sig = ecdsaSign(msg, ephemeralPrivateKey);
+            return Buffer.concat([Buffer.from(sig.signature), Buffer.from([sig.recid])]);
+        }
+
+        case 'EcdsaRecover': {
+            const sig = Buffer.from(input.sig, 'hex');
+            const msg = Buffer.from(input.msg, 'hex');
+
+            // Uncompressed key without the 04 prefix
+            return Buffer.from(ecdsaRecover(sig.subarray(0, 64), sig[64], msg, false)).subarray(1);
+        }
+
+        case 'TaggedKdf': {
+            const msg = Buffer.from(input.msg, 'hex');
+            const sharedMacData = Buffer.from(input.macData, 'hex');
+            const rpk = Buffer.from(input.remotePublicKey, 'hex');
+            const privateKey = Buffer.from(input.ephemeralPrivateKey, 'hex');
+            const IV = Buffer.from(input.iv, 'hex');
+            return taggedKdf(rpk, msg, sharedMacData, privateKey, IV);
+        }
+
+        case 'EciesDecrypt': {
+            const privateKey = Buffer.from(input.privateKey, 'hex');
+            const msg = Buffer.from(input.msg, 'hex');
+            const sharedMacData = Buffer.from(input.macData, 'hex');
+            return eciesDecrypt(privateKey, msg, sharedMacData);
+        }
+
+        case 'ConcatKdf': {
+            const keyMaterial = Buffer.from(input.keyMaterial, 'hex');
+            return concatKDF(keyMaterial, input.keyLength);
+        }
+
+        default:
+            throw new Error(`Unknown request type: ${input.type}`);
     }
-        break;
+};
 
-    default:
-        throw new Error('Unreachable!');
-}
+// One JSON request per line on stdin, one JSON response per line on stdout
+// Responses are paired with requests by the id field
+readline.createInterface({ input: process.stdin }).on('line', (line) => {
+    let id = null;
+    try {
+        const input = JSON.parse(line);
+        id = input.id;
+        console.log(JSON.stringify({ id, result: Buffer.from(handle(input)).toString('hex') }));
+    } catch (e) {
+        console.log(JSON.stringify({ id, error: e.message }));
+    }
+});
@@ -1,14 +1,28 @@
 //! Command like argument parsing library
-//! Only remote ID is required, other are predefined
+//! Only remote ID is required unless listening or exporting the keystore,
+//! other are predefined
 
-use clap::Parser;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use clap::{Parser, ValueEnum};
+
+use crate::consts::NODEKEY_FILE;
+use crate::rlpx::limits::{
+    MAX_BUFFER_SIZE, MAX_DECOMPRESSED_SIZE, MAX_FRAME_SIZE, MAX_HANDSHAKE_SIZE,
+};
+use crate::rlpx::Capability;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
     /// Remote P2P node ID (hex)
+    #[arg(short, long, required_unless_present_any = ["listen", "export_keystore"])]
+    pub remote_id: Option<String>,
+
+    /// Accepts incoming connections on the address instead of dialling
     #[arg(short, long)]
-    pub remote_id: String,
+    pub listen: Option<SocketAddr>,
 
     /// Remote P2P node address
     #[arg(short, long, default_value = "127.0.0.1")]
@@ -17,4 +31,85 @@ pub struct Args {
     /// Remote P2P node port
     #[arg(short, long, default_value_t = 30303)]
     pub port: u16,
+
+    /// Supported capability as name/version, or name/version/length when the
+    /// number of its messages is not known, can be repeated
+    #[arg(long = "capability", default_value = "eth/66")]
+    pub capabilities: Vec<Capability>,
+
+    /// Dials without the discovery ping first, for peers without discovery
+    /// like another instance of this node
+    #[arg(long)]
+    pub skip_ping: bool,
+
+    /// Sends the legacy pre-EIP8 auth message, for peers without EIP8 support
+    #[arg(long)]
+    pub legacy_handshake: bool,
+
+    /// Seconds to wait for the TCP connection
+    #[arg(long, default_value_t = 5)]
+    pub connect_timeout: u64,
+
+    /// Seconds to wait for the Ack, or for the Auth when listening
+    #[arg(long, default_value_t = 5)]
+    pub ack_timeout: u64,
+
+    /// Seconds to wait for the remote Hello
+    #[arg(long, default_value_t = 5)]
+    pub hello_timeout: u64,
+
+    /// Seconds the whole session setup can take, up to the exchanged Hellos
+    #[arg(long, default_value_t = 20)]
+    pub setup_timeout: u64,
+
+    /// Biggest accepted auth or ack message in bytes
+    #[arg(long, default_value_t = MAX_HANDSHAKE_SIZE)]
+    pub max_handshake_size: usize,
+
+    /// Biggest accepted frame in bytes, the protocol allows at most 16 MiB
+    #[arg(long, default_value_t = MAX_FRAME_SIZE)]
+    pub max_frame_size: usize,
+
+    /// Biggest accepted message after snappy decompression in bytes
+    #[arg(long, default_value_t = MAX_DECOMPRESSED_SIZE)]
+    pub max_decompressed_size: usize,
+
+    /// Most bytes buffered per connection before they are decoded, it has to
+    /// fit the biggest frame including its header and MACs
+    #[arg(long, default_value_t = MAX_BUFFER_SIZE)]
+    pub max_buffer_size: usize,
+
+    /// Implementation of the cryptography used in the handshake
+    #[arg(short, long, value_enum, default_value_t = Crypto::Native)]
+    pub crypto: Crypto,
+
+    /// Node key file, generated when it does not exist
+    #[arg(long, default_value = NODEKEY_FILE)]
+    pub nodekey: PathBuf,
+
+    /// Node key as hex, takes priority over the node key file
+    #[arg(long, env = "P2P_NODEKEY_HEX")]
+    pub nodekey_hex: Option<String>,
+
+    /// Encrypted node key file, takes priority over other node key sources
+    #[arg(long)]
+    pub keystore: Option<PathBuf>,
+
+    /// Stores the node key into a new encrypted keystore file and exits
+    #[arg(long)]
+    pub export_keystore: Option<PathBuf>,
+
+    /// Keystore password, prompted for when not set
+    #[arg(long, env = "P2P_KEYSTORE_PASSWORD", hide_env_values = true)]
+    pub keystore_password: Option<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Crypto {
+    /// Rust implementation
+    Native,
+    /// NodeJS implementation in `./auth`
+    Node,
+    /// Runs both and fails on any difference
+    Differential,
 }
@@ -1,7 +1,4 @@
 //! Global constants
-//! These could be included as a file that is loaded at the start of the
-//! application It could be done in combination with other arguments
-//! For simplicity it is included in the executable
 
-pub const PRIVATE_KEY_HEX: &str =
-    "d772e3d6a001a38064dd23964dd2836239fa0e6cec8b28972a87460a17210fe9";
+/// Default location of the node key, relative to the working directory
+pub const NODEKEY_FILE: &str = "nodekey";
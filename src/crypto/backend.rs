@@ -0,0 +1,263 @@
+//! Interchangeable implementations of the cryptography used by RLPx
+//! Native is the default, NodeJS (`ffi::EncFfi`) is kept as a reference and
+//! Differential runs both of them and compares the results
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use fehler::{throw, throws};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use secp256k1::SecretKey;
+use zeroize::Zeroizing;
+
+use super::{concat_kdf, ecdhx, ecies, recover, sign};
+use crate::error::{BackendMismatch, Result};
+use crate::Error;
+
+#[async_trait]
+pub trait CryptoBackend: Send + Sync {
+    /// Name used in logs and errors
+    fn name(&self) -> &'static str;
+
+    /// ECDH agreement returning only the x coordinate of the shared point
+    async fn ecdh(&self, private_key: &[u8], public_key: &[u8]) -> Result<Zeroizing<Vec<u8>>>;
+
+    /// Recoverable signature in format r || s || recovery-id
+    async fn sign(&self, private_key: &[u8], msg: &[u8]) -> Result<Bytes>;
+
+    /// Public key (without the 04 prefix) recovered from the signature
+    async fn recover(&self, sig: &[u8], msg: &[u8]) -> Result<Bytes>;
+
+    /// ECIES encryption with given ephemeral key and IV
+    async fn ecies_encrypt_with(
+        &self,
+        remote_pk: &[u8],
+        eph_private_key: &[u8],
+        iv: &[u8; 16],
+        msg: &[u8],
+        mac_data: &[u8],
+    ) -> Result<Bytes>;
+
+    /// ECIES decryption of the message created by `ecies_encrypt`
+    /// Fails if the tag does not match the message and shared MAC data
+    async fn ecies_decrypt(&self, private_key: &[u8], msg: &[u8], mac_data: &[u8])
+        -> Result<Bytes>;
+
+    /// NIST SP 800-56 Concatenation Key Derivation Function
+    async fn kdf(&self, key_material: &[u8], key_len: usize) -> Result<Zeroizing<Vec<u8>>>;
+
+    /// ECIES encryption with random ephemeral key and IV
+    async fn ecies_encrypt(&self, remote_pk: &[u8], msg: &[u8], mac_data: &[u8]) -> Result<Bytes> {
+        let eph_private_key = Zeroizing::new(SecretKey::new(&mut OsRng).secret_bytes());
+
+        let mut iv = [0; 16];
+        OsRng.fill_bytes(&mut iv);
+
+        self.ecies_encrypt_with(remote_pk, &*eph_private_key, &iv, msg, mac_data).await
+    }
+}
+
+pub struct Native;
+
+#[async_trait]
+impl CryptoBackend for Native {
+    fn name(&self) -> &'static str {
+        "native"
+    }
+
+    async fn ecdh(&self, private_key: &[u8], public_key: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        Ok(Zeroizing::new(ecdhx(private_key, public_key)?.to_vec()))
+    }
+
+    async fn sign(&self, private_key: &[u8], msg: &[u8]) -> Result<Bytes> {
+        Ok(Bytes::copy_from_slice(&sign(private_key, msg)?))
+    }
+
+    async fn recover(&self, sig: &[u8], msg: &[u8]) -> Result<Bytes> {
+        Ok(Bytes::copy_from_slice(&recover(sig, msg)?))
+    }
+
+    async fn ecies_encrypt_with(
+        &self,
+        remote_pk: &[u8],
+        eph_private_key: &[u8],
+        iv: &[u8; 16],
+        msg: &[u8],
+        mac_data: &[u8],
+    ) -> Result<Bytes> {
+        ecies::encrypt_with(remote_pk, eph_private_key, iv, msg, mac_data)
+    }
+
+    async fn ecies_decrypt(
+        &self,
+        private_key: &[u8],
+        msg: &[u8],
+        mac_data: &[u8],
+    ) -> Result<Bytes> {
+        ecies::decrypt(private_key, msg, mac_data)
+    }
+
+    async fn kdf(&self, key_material: &[u8], key_len: usize) -> Result<Zeroizing<Vec<u8>>> {
+        Ok(concat_kdf(key_material, key_len))
+    }
+}
+
+/// Runs every operation on both backends and fails on any difference
+/// Result of the reference backend is returned
+pub struct Differential {
+    reference: Arc<dyn CryptoBackend>,
+    candidate: Arc<dyn CryptoBackend>,
+}
+
+impl Differential {
+    pub fn new(reference: Arc<dyn CryptoBackend>, candidate: Arc<dyn CryptoBackend>) -> Self {
+        Self {
+            reference,
+            candidate,
+        }
+    }
+
+    #[throws]
+    fn compare<T>(&self, op: &'static str, reference: Result<T>, candidate: Result<T>) -> T
+    where
+        T: AsRef<[u8]> + PartialEq,
+    {
+        match (reference, candidate) {
+            (Ok(reference), Ok(candidate)) if reference == candidate => reference,
+            (Err(err), Err(_)) => throw!(err),
+            (reference, candidate) => {
+                // Results can be secrets, so only their lengths are logged
+                log::error!(
+                    "{op} mismatch: {} returned {}, {} returned {}",
+                    self.reference.name(),
+                    describe(&reference),
+                    self.candidate.name(),
+                    describe(&candidate),
+                );
+
+                throw!(BackendMismatch {
+                    op,
+                    reference: self.reference.name(),
+                    candidate: self.candidate.name(),
+                }
+                .build());
+            }
+        }
+    }
+}
+
+fn describe<T: AsRef<[u8]>>(res: &Result<T>) -> String {
+    match res {
+        Ok(value) => format!("{} bytes", value.as_ref().len()),
+        Err(err) => format!("error \"{err}\""),
+    }
+}
+
+#[async_trait]
+impl CryptoBackend for Differential {
+    fn name(&self) -> &'static str {
+        "differential"
+    }
+
+    async fn ecdh(&self, private_key: &[u8], public_key: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        let (reference, candidate) = tokio::join!(
+            self.reference.ecdh(private_key, public_key),
+            self.candidate.ecdh(private_key, public_key),
+        );
+        self.compare("ecdh", reference, candidate)
+    }
+
+    async fn sign(&self, private_key: &[u8], msg: &[u8]) -> Result<Bytes> {
+        let (reference, candidate) = tokio::join!(
+            self.reference.sign(private_key, msg),
+            self.candidate.sign(private_key, msg),
+        );
+        self.compare("sign", reference, candidate)
+    }
+
+    async fn recover(&self, sig: &[u8], msg: &[u8]) -> Result<Bytes> {
+        let (reference, candidate) =
+            tokio::join!(self.reference.recover(sig, msg), self.candidate.recover(sig, msg));
+        self.compare("recover", reference, candidate)
+    }
+
+    async fn ecies_encrypt_with(
+        &self,
+        remote_pk: &[u8],
+        eph_private_key: &[u8],
+        iv: &[u8; 16],
+        msg: &[u8],
+        mac_data: &[u8],
+    ) -> Result<Bytes> {
+        let (reference, candidate) = tokio::join!(
+            self.reference.ecies_encrypt_with(remote_pk, eph_private_key, iv, msg, mac_data),
+            self.candidate.ecies_encrypt_with(remote_pk, eph_private_key, iv, msg, mac_data),
+        );
+        self.compare("ecies_encrypt", reference, candidate)
+    }
+
+    async fn ecies_decrypt(
+        &self,
+        private_key: &[u8],
+        msg: &[u8],
+        mac_data: &[u8],
+    ) -> Result<Bytes> {
+        let (reference, candidate) = tokio::join!(
+            self.reference.ecies_decrypt(private_key, msg, mac_data),
+            self.candidate.ecies_decrypt(private_key, msg, mac_data),
+        );
+        self.compare("ecies_decrypt", reference, candidate)
+    }
+
+    async fn kdf(&self, key_material: &[u8], key_len: usize) -> Result<Zeroizing<Vec<u8>>> {
+        let (reference, candidate) = tokio::join!(
+            self.reference.kdf(key_material, key_len),
+            self.candidate.kdf(key_material, key_len),
+        );
+        self.compare("kdf", reference, candidate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::EncFfi;
+    use crate::utils::{id2pk, pub_key};
+
+    // Static keys A and B from the EIP-8 test vectors
+    const PRIVATE_KEY: &str = "49a7b37aa6f6645917e7b807e9d1c00d4fa71f18343b0d4122a4d2df64dd6fee";
+    const REMOTE_KEY: &str = "b71c71a67e1177ad4e901695e1b4b9ee17ae16c6668d313eac2f96dbcda3f291";
+
+    /// Every operation fails on any difference between NodeJS and native
+    #[tokio::test]
+    #[ignore = "needs nodejs and `node_modules` installed in `auth`"]
+    async fn node_matches_native() {
+        let crypto = Differential::new(Arc::new(EncFfi::new().unwrap()), Arc::new(Native));
+
+        let private_key = hex::decode(PRIVATE_KEY).unwrap();
+        let remote_key = hex::decode(REMOTE_KEY).unwrap();
+        let remote_pk = id2pk(&pub_key(&remote_key).unwrap());
+        let msg = [0x42; 32];
+
+        crypto.ecdh(&private_key, &remote_pk).await.unwrap();
+
+        let sig = crypto.sign(&private_key, &msg).await.unwrap();
+        let recovered = crypto.recover(&sig, &msg).await.unwrap();
+        assert_eq!(recovered, pub_key(&private_key).unwrap().as_slice());
+
+        let (iv, mac_data) = ([0x07; 16], [0x01, 0x02]);
+        let enc = crypto
+            .ecies_encrypt_with(&remote_pk, &private_key, &iv, b"hello", &mac_data)
+            .await
+            .unwrap();
+        let dec = crypto.ecies_decrypt(&remote_key, &enc, &mac_data).await.unwrap();
+        assert_eq!(dec, b"hello".as_slice());
+
+        // NodeJS `concatKDF` returns too few bytes for lengths 33..=56
+        for len in [16, 32, 60] {
+            crypto.kdf(&private_key, len).await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Elliptic Curve Integrated Encryption Scheme as used by RLPx
+//! Output is the same as `taggedKdf` from @ethereumjs/devp2p
+//!
+//! ecies = ephemeral-pubk || iv || aes128ctr(ekey, iv, msg) || tag
+//! ekey || mkey = concat_kdf(ecdhx(ephemeral-privk, remote-pubk), 32)
+//! tag = hmac_sha256(sha256(mkey), iv || encrypted-msg || shared-mac-data)
+
+use aes::cipher::{KeyIvInit, StreamCipher};
+use bytes::{BufMut, Bytes, BytesMut};
+use fehler::{throw, throws};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use super::{concat_kdf, ecdhx};
+use crate::error::{EciesLength, EciesTag};
+use crate::utils::{id2pk, pub_key};
+use crate::Error;
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+type HmacSha256 = Hmac<Sha256>;
+
+/// Bytes added to the message: public key (65), IV (16) and tag (32)
+pub const OVERHEAD: usize = 65 + 16 + 32;
+
+/// Encrypts the message with given ephemeral key and IV
+/// Random ones are chosen by `CryptoBackend::ecies_encrypt`
+#[throws]
+pub fn encrypt_with(
+    remote_pk: &[u8],
+    eph_private_key: &[u8],
+    iv: &[u8; 16],
+    msg: &[u8],
+    mac_data: &[u8],
+) -> Bytes {
+    let key = concat_kdf(&*ecdhx(eph_private_key, remote_pk)?, 32);
+    let (ekey, mkey) = key.split_at(16);
+
+    let mut data = msg.to_vec();
+    Aes128Ctr::new_from_slices(ekey, iv)?.apply_keystream(&mut data);
+
+    let tag = {
+        let mut hmac = HmacSha256::new_from_slice(&Sha256::digest(mkey))?;
+        hmac.update(iv);
+        hmac.update(&data);
+        hmac.update(mac_data);
+        hmac.finalize().into_bytes()
+    };
+
+    let mut res = BytesMut::with_capacity(msg.len() + OVERHEAD);
+    res.put(id2pk(&pub_key(eph_private_key)?));
+    res.put(iv.as_slice());
+    res.put(data.as_slice());
+    res.put(tag.as_slice());
+    res.freeze()
+}
+
+/// Decrypts the message created by `encrypt_with`
+/// Tag is verified in constant time before anything is decrypted
+#[throws]
+pub fn decrypt(private_key: &[u8], msg: &[u8], mac_data: &[u8]) -> Bytes {
+    if msg.len() < OVERHEAD {
+        throw!(EciesLength { len: msg.len() }.build());
+    }
+
+    let (pub_key, rest) = msg.split_at(65);
+    let (iv, rest) = rest.split_at(16);
+    let (data, tag) = rest.split_at(rest.len() - 32);
+
+    let key = concat_kdf(&*ecdhx(private_key, pub_key)?, 32);
+    let (ekey, mkey) = key.split_at(16);
+
+    let mut hmac = HmacSha256::new_from_slice(&Sha256::digest(mkey))?;
+    hmac.update(iv);
+    hmac.update(data);
+    hmac.update(mac_data);
+    hmac.verify_slice(tag).map_err(|_| EciesTag.build())?;
+
+    let mut data = data.to_vec();
+    Aes128Ctr::new_from_slices(ekey, iv)?.apply_keystream(&mut data);
+    Bytes::from(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPH_KEY: [u8; 32] = [0x01; 32];
+    const IV: [u8; 16] = [0x02; 16];
+    // Static key B from the EIP-8 test vectors
+    const REMOTE_KEY: &str = "b71c71a67e1177ad4e901695e1b4b9ee17ae16c6668d313eac2f96dbcda3f291";
+
+    const MSG: &[u8] = b"auth body";
+    const MAC_DATA: [u8; 2] = [0x00, 0x7a];
+
+    fn encrypted() -> Vec<u8> {
+        let remote_pk = id2pk(&pub_key(&hex::decode(REMOTE_KEY).unwrap()).unwrap());
+        encrypt_with(&remote_pk, &EPH_KEY, &IV, MSG, &MAC_DATA).unwrap().to_vec()
+    }
+
+    fn decrypted(msg: &[u8], mac_data: &[u8]) -> Result<Bytes, Error> {
+        decrypt(&hex::decode(REMOTE_KEY).unwrap(), msg, mac_data)
+    }
+
+    /// Output of `taggedKdf` from `auth/ffi.js` with the same inputs
+    #[test]
+    fn encrypt_matches_js() {
+        assert_eq!(
+            hex::encode(encrypted()),
+            concat!(
+                "041b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f",
+                "70beaf8f588b541507fed6a642c5ab42dfdf8120a7f639de5122d47a69a8e8d1",
+                "02020202020202020202020202020202",
+                "95f4744952060e0317",
+                "9a1a8e88474eebd220f41eb22f3d1e023aafd6d45cf6951b86d34ab136e43441",
+            )
+        );
+    }
+
+    #[test]
+    fn roundtrip() {
+        assert_eq!(decrypted(&encrypted(), &MAC_DATA).unwrap(), MSG);
+    }
+
+    #[test]
+    fn tampered_ciphertext() {
+        let mut msg = encrypted();
+        msg[65 + 16] ^= 1;
+        assert!(matches!(decrypted(&msg, &MAC_DATA), Err(Error::EciesTag)));
+    }
+
+    #[test]
+    fn tampered_tag() {
+        let mut msg = encrypted();
+        *msg.last_mut().unwrap() ^= 1;
+        assert!(matches!(decrypted(&msg, &MAC_DATA), Err(Error::EciesTag)));
+    }
+
+    /// Shared MAC data is the size prefix of the EIP8 messages
+    #[test]
+    fn wrong_mac_data() {
+        assert!(matches!(decrypted(&encrypted(), &[0x00, 0x7b]), Err(Error::EciesTag)));
+    }
+
+    #[test]
+    fn too_short() {
+        let res = decrypted(&encrypted()[..OVERHEAD - 1], &MAC_DATA);
+        assert!(matches!(res, Err(Error::EciesLength { len }) if len == OVERHEAD - 1));
+    }
+}
@@ -0,0 +1,112 @@
+//! Native cryptographic primitives used by the RLPx handshake
+//! Same as the NodeJS helpers from `auth/ffi.js`
+
+use fehler::{throw, throws};
+use secp256k1::ecdh::shared_secret_point;
+use secp256k1::ecdsa::{RecoverableSignature, RecoveryId};
+use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::Error;
+
+pub mod backend;
+pub mod ecies;
+
+pub use backend::{CryptoBackend, Differential, Native};
+
+/// ECDH agreement that returns only the x coordinate of the shared point
+/// Same as `ecdhX` from @ethereumjs/devp2p, no hashing is applied
+#[throws]
+pub fn ecdhx(private_key: &[u8], public_key: &[u8]) -> Zeroizing<[u8; 32]> {
+    let sk = SecretKey::from_slice(private_key)?;
+    let pk = PublicKey::from_slice(public_key)?;
+    let point = Zeroizing::new(shared_secret_point(&pk, &sk));
+    Zeroizing::new(point[..32].try_into()?)
+}
+
+/// NIST SP 800-56 Concatenation Key Derivation Function
+/// key = sha256(1 || key-material) || sha256(2 || key-material) || ...
+pub fn concat_kdf(key_material: &[u8], key_len: usize) -> Zeroizing<Vec<u8>> {
+    // Never reallocated, so no copy of the key is left behind
+    let mut res = Zeroizing::new(Vec::with_capacity(key_len + 32));
+    let mut counter = 1_u32;
+
+    while res.len() < key_len {
+        let hash = Sha256::new()
+            .chain_update(counter.to_be_bytes())
+            .chain_update(key_material)
+            .finalize();
+        res.extend_from_slice(hash.as_slice());
+        counter += 1;
+    }
+
+    res.truncate(key_len);
+    res
+}
+
+/// Recoverable ECDSA signature of a 32 byte message
+/// sig = r || s || recovery-id
+#[throws]
+pub fn sign(private_key: &[u8], msg: &[u8]) -> [u8; 65] {
+    let secp = Secp256k1::signing_only();
+    let sig = secp
+        .sign_ecdsa_recoverable(&Message::from_slice(msg)?, &SecretKey::from_slice(private_key)?);
+    let (rec_id, sig) = sig.serialize_compact();
+
+    let mut res = [0; 65];
+    res[..64].copy_from_slice(&sig);
+    res[64] = rec_id.to_i32() as u8;
+    res
+}
+
+/// Recovers the public key (without the 04 prefix) of the signer
+/// Inverse of the `sign` function
+#[throws]
+pub fn recover(sig: &[u8], msg: &[u8]) -> [u8; 64] {
+    let [sig @ .., rec_id] = sig else {
+        throw!(secp256k1::Error::InvalidSignature);
+    };
+
+    let sig = RecoverableSignature::from_compact(sig, RecoveryId::from_i32(*rec_id as i32)?)?;
+    let secp = Secp256k1::verification_only();
+    let pk = secp.recover_ecdsa(&Message::from_slice(msg)?, &sig)?;
+
+    // cut 04
+    pk.serialize_uncompressed()[1..].try_into()?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Static keys A and B from the EIP-8 test vectors, outputs from `auth/ffi.js`
+    const PRIVATE_KEY: &str = "49a7b37aa6f6645917e7b807e9d1c00d4fa71f18343b0d4122a4d2df64dd6fee";
+    const PUBLIC_KEY: &str = "04ca634cae0d49acb401d8a4c6b6fe8c55b70d115bf400769cc1400f3258cd31387574077f301b421bc84df7266c44e9e6d569fc56be00812904767bf5ccd1fc7f";
+
+    #[test]
+    fn ecdhx_matches_js() {
+        let shared = ecdhx(&hex::decode(PRIVATE_KEY).unwrap(), &hex::decode(PUBLIC_KEY).unwrap());
+        assert_eq!(
+            hex::encode(*shared.unwrap()),
+            "2d21423c1dc3355da36e7f2c2b530eeffcf0680f93201a958b2ec3a7d04958e6"
+        );
+    }
+
+    // `concatKDF` in JS returns too few bytes for lengths 33..=56, so the
+    // partial block is checked with 16 and 60 bytes
+    #[test]
+    fn concat_kdf_matches_js() {
+        let key_material = hex::decode(PRIVATE_KEY).unwrap();
+        for (len, expected) in [
+            (32, "b81b01b8e73f2066b0aadf2055e11b3e4c156e27ce4b9452f1c1a0a8c962b9fc"),
+            (16, "b81b01b8e73f2066b0aadf2055e11b3e"),
+            (
+                60,
+                "b81b01b8e73f2066b0aadf2055e11b3e4c156e27ce4b9452f1c1a0a8c962b9fc8311274ead1b830e2b045812fe4c77aec0fb57533d716058e517fca8",
+            ),
+        ] {
+            assert_eq!(hex::encode(concat_kdf(&key_material, len)), expected);
+        }
+    }
+}
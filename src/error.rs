@@ -12,6 +12,8 @@ use ecies::SecpError;
 use hex::FromHexError;
 use snafu::Snafu;
 
+use crate::rlpx::types::DisconnectReason;
+
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub), context(suffix(false)))]
 pub enum Error {
@@ -44,6 +46,106 @@ pub enum Error {
 
     #[snafu(display("Secp256k1 error: {source}"), context(false))]
     Secp256k1 { source: secp256k1::Error },
+
+    #[snafu(display("Rlp error: {source}"), context(false))]
+    Rlp { source: rlp::DecoderError },
+
+    #[snafu(display("Snappy error: {source}"), context(false))]
+    Snappy { source: snap::Error },
+
+    #[snafu(display("ECIES message is too short: {len} bytes"))]
+    EciesLength { len: usize },
+
+    #[snafu(display("ECIES tag does not match the message"))]
+    EciesTag,
+
+    #[snafu(display("Frame of {size} bytes is over the maximum of {max} bytes"))]
+    FrameSize { size: usize, max: usize },
+
+    #[snafu(display("Message decompresses to {size} bytes, maximum is {max} bytes"))]
+    DecompressedSize { size: usize, max: usize },
+
+    #[snafu(display("Invalid capability {value}, expected name/version or name/version/length"))]
+    CapabilityFormat { value: String },
+
+    #[snafu(display("Invalid {field} in the handshake message"))]
+    HandshakeField { field: &'static str },
+
+    #[snafu(display("Handshake message of {size} bytes is shorter than {min} bytes"))]
+    HandshakeSize { size: usize, min: usize },
+
+    #[snafu(display("Handshake message of {size} bytes is over the limit of {max} bytes"))]
+    HandshakeLimit { size: usize, max: usize },
+
+    #[snafu(display("{size} bytes are buffered, over the limit of {max} bytes"))]
+    BufferLimit { size: usize, max: usize },
+
+    #[snafu(display(
+        "Buffer limit of {max} bytes is smaller than the biggest frame of {frame} bytes"
+    ))]
+    InvalidBufferLimit { max: usize, frame: usize },
+
+    #[snafu(display("Timed out after {secs}s waiting for the {phase}"))]
+    Timeout { phase: &'static str, secs: u64 },
+
+    #[snafu(display("Connection closed after {received} of {expected} bytes of the {message}"))]
+    Truncated {
+        message: &'static str,
+        expected: usize,
+        received: usize,
+    },
+
+    #[snafu(display("Peer disconnected: {reason}"))]
+    Disconnected { reason: DisconnectReason },
+
+    #[snafu(display("Peer did not answer the ping"))]
+    PingTimeout,
+
+    #[snafu(display("Expected message {expected:#04x}, received {received:#04x}"))]
+    UnexpectedMessage { expected: u64, received: u64 },
+
+    #[snafu(display("Remote node ID {received} does not match the dialled {expected}"))]
+    NodeIdMismatch { expected: String, received: String },
+
+    #[snafu(display("Frame header MAC does not match"))]
+    HeaderMac,
+
+    #[snafu(display("Malformed frame header data: {source}"))]
+    FrameHeader { source: rlp::DecoderError },
+
+    #[snafu(display("Unsupported frame: {reason}"))]
+    UnsupportedFrame { reason: &'static str },
+
+    #[snafu(display("Frame MAC does not match"))]
+    FrameMac,
+
+    #[snafu(display("Unsupported keystore cipher: {cipher}"))]
+    KeystoreCipher { cipher: String },
+
+    #[snafu(display("Unsupported keystore version {version}, only 3 is supported"))]
+    KeystoreVersion { version: u32 },
+
+    #[snafu(display("Unsupported keystore KDF parameters"))]
+    KeystoreParams,
+
+    #[snafu(display("Keystore MAC does not match, wrong password?"))]
+    KeystoreMac,
+
+    #[snafu(display("Crypto worker has exited"))]
+    WorkerExited,
+
+    #[snafu(display("Crypto worker failed: {msg}"))]
+    WorkerFailed { msg: String },
+
+    #[snafu(display("Crypto worker printed invalid response: {line}"))]
+    WorkerGarbage { line: String },
+
+    #[snafu(display("Crypto backends {reference} and {candidate} differ in {op}"))]
+    BackendMismatch {
+        op: &'static str,
+        reference: &'static str,
+        candidate: &'static str,
+    },
 }
 
 /// Either use this type or the fehler library
@@ -1,61 +1,120 @@
 //! NodeJS FFI binding for parts implemented in different language
-//! Once the dependencies are switched to rust versions than this is no longer
-//! required
+//! Kept as a reference implementation of the `CryptoBackend`
 
+use async_trait::async_trait;
 use bytes::Bytes;
 use fehler::throws;
+use serde::Serialize;
+use zeroize::Zeroizing;
 
-use crate::utils::node;
+use crate::crypto::CryptoBackend;
+use crate::error::Result;
 use crate::Error;
 
 mod types;
 use types::*;
 
-pub struct EncFfi;
+mod worker;
+use worker::Worker;
+
+pub struct EncFfi {
+    worker: Worker,
+}
 
 impl EncFfi {
+    /// Starts the NodeJS worker, it is stopped once this is dropped
     #[throws]
-    pub async fn ecdsa_sign(eph_private_key: &[u8], ecdhx: &[u8]) -> Bytes {
-        let args = EcdsaSign::builder()
-            .ephemeral_private_key(hex::encode(eph_private_key))
-            .msg(hex::encode(ecdhx))
-            .build();
+    pub fn new() -> Self {
+        Self {
+            worker: Worker::spawn()?,
+        }
+    }
 
-        let res = hex::decode(node(serde_json::to_string(&args)?).await?)?;
-        Bytes::copy_from_slice(res.as_slice())
+    #[throws]
+    async fn call(&self, input: &(impl Serialize + Sync)) -> Bytes {
+        let res = hex::decode(self.worker.call(input).await?)?;
+        Bytes::from(res)
     }
 
+    /// Same as `call` for secrets, both the hex and decoded result are
+    /// cleared on drop
     #[throws]
-    pub async fn ecdhx(private_key: &[u8], public_key: &[u8]) -> Bytes {
-        let args = Ecdhx::builder()
+    async fn call_secret(&self, input: &(impl Serialize + Sync)) -> Zeroizing<Vec<u8>> {
+        let res = Zeroizing::new(self.worker.call(input).await?);
+        Zeroizing::new(hex::decode(&*res)?)
+    }
+}
+
+#[async_trait]
+impl CryptoBackend for EncFfi {
+    fn name(&self) -> &'static str {
+        "node"
+    }
+
+    async fn ecdh(&self, private_key: &[u8], public_key: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        let input = Ecdhx::builder()
             .private_key(hex::encode(private_key))
             .public_key(hex::encode(public_key))
             .build();
 
-        let res = hex::decode(node(serde_json::to_string(&args)?).await?)?;
-        Bytes::copy_from_slice(res.as_slice())
+        self.call_secret(&input).await
     }
 
-    #[throws]
-    pub async fn tagged_kdf(msg: &[u8], client_pk: &[u8], mac_data: &[u8]) -> Bytes {
+    async fn sign(&self, private_key: &[u8], msg: &[u8]) -> Result<Bytes> {
+        let input = EcdsaSign::builder()
+            .ephemeral_private_key(hex::encode(private_key))
+            .msg(hex::encode(msg))
+            .build();
+
+        self.call(&input).await
+    }
+
+    async fn recover(&self, sig: &[u8], msg: &[u8]) -> Result<Bytes> {
+        let input = EcdsaRecover::builder().sig(hex::encode(sig)).msg(hex::encode(msg)).build();
+
+        self.call(&input).await
+    }
+
+    async fn ecies_encrypt_with(
+        &self,
+        remote_pk: &[u8],
+        eph_private_key: &[u8],
+        iv: &[u8; 16],
+        msg: &[u8],
+        mac_data: &[u8],
+    ) -> Result<Bytes> {
         let input = TaggedKdf::builder()
             .msg(hex::encode(msg))
-            .remote_public_key(hex::encode(client_pk))
+            .remote_public_key(hex::encode(remote_pk))
+            .ephemeral_private_key(hex::encode(eph_private_key))
+            .iv(hex::encode(iv))
             .mac_data(hex::encode(mac_data))
             .build();
 
-        let enc = hex::decode(node(serde_json::to_string(&input)?).await?)?;
-        Bytes::copy_from_slice(enc.as_slice())
+        self.call(&input).await
     }
 
-    #[throws]
-    pub async fn concat_kdf(msg: &[u8], private_key: &[u8]) -> Bytes {
-        let input = ConcatKdf::builder()
+    async fn ecies_decrypt(
+        &self,
+        private_key: &[u8],
+        msg: &[u8],
+        mac_data: &[u8],
+    ) -> Result<Bytes> {
+        let input = EciesDecrypt::builder()
             .private_key(hex::encode(private_key))
             .msg(hex::encode(msg))
+            .mac_data(hex::encode(mac_data))
+            .build();
+
+        self.call(&input).await
+    }
+
+    async fn kdf(&self, key_material: &[u8], key_len: usize) -> Result<Zeroizing<Vec<u8>>> {
+        let input = ConcatKdf::builder()
+            .key_material(hex::encode(key_material))
+            .key_length(key_len)
             .build();
 
-        let enc = hex::decode(node(serde_json::to_string(&input)?).await?)?;
-        Bytes::copy_from_slice(enc.as_slice())
+        self.call_secret(&input).await
     }
 }
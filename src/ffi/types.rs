@@ -1,11 +1,29 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use typed_builder::TypedBuilder;
 
+/// Worker request, the input is merged with the ID
+#[derive(Serialize)]
+pub struct Request<'a, T> {
+    pub id: u64,
+    #[serde(flatten)]
+    pub input: &'a T,
+}
+
+/// Worker response, either result or error is set
+#[derive(Deserialize)]
+pub struct Response {
+    pub id: u64,
+    pub result: Option<String>,
+    pub error: Option<String>,
+}
+
 #[derive(Serialize)]
 pub enum MsgType {
     Ecdhx,
     EcdsaSign,
+    EcdsaRecover,
     TaggedKdf,
+    EciesDecrypt,
     ConcatKdf,
 }
 
@@ -30,6 +48,16 @@ pub struct EcdsaSign {
     pub msg: String,
 }
 
+#[derive(Serialize, TypedBuilder)]
+#[serde(rename_all = "camelCase")]
+pub struct EcdsaRecover {
+    #[serde(rename = "type")]
+    #[builder(default=MsgType::EcdsaRecover)]
+    pub t: MsgType,
+    pub sig: String,
+    pub msg: String,
+}
+
 #[derive(Serialize, TypedBuilder)]
 #[serde(rename_all = "camelCase")]
 pub struct TaggedKdf {
@@ -39,15 +67,28 @@ pub struct TaggedKdf {
 
     pub msg: String,
     pub remote_public_key: String,
+    pub ephemeral_private_key: String,
+    pub iv: String,
     pub mac_data: String,
 }
 
 #[derive(Serialize, TypedBuilder)]
 #[serde(rename_all = "camelCase")]
-pub struct ConcatKdf {
+pub struct EciesDecrypt {
     #[serde(rename = "type")]
-    #[builder(default=MsgType::ConcatKdf)]
+    #[builder(default=MsgType::EciesDecrypt)]
     pub t: MsgType,
     pub private_key: String,
     pub msg: String,
+    pub mac_data: String,
+}
+
+#[derive(Serialize, TypedBuilder)]
+#[serde(rename_all = "camelCase")]
+pub struct ConcatKdf {
+    #[serde(rename = "type")]
+    #[builder(default=MsgType::ConcatKdf)]
+    pub t: MsgType,
+    pub key_material: String,
+    pub key_length: usize,
 }
@@ -0,0 +1,178 @@
+//! Long-lived NodeJS process running `auth/ffi.js`
+//! Requests and responses are newline delimited JSON objects paired by ID,
+//! so a single worker can be shared by any number of concurrent sessions
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use fehler::{throw, throws};
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::{oneshot, Mutex as AsyncMutex};
+
+use super::types::{Request, Response};
+use crate::error::{Result, WorkerExited, WorkerFailed, WorkerGarbage};
+use crate::Error;
+
+/// Requests waiting for a response, `None` once the worker has exited
+type Pending = Arc<Mutex<Option<HashMap<u64, oneshot::Sender<Result<String>>>>>>;
+
+pub struct Worker {
+    stdin: AsyncMutex<ChildStdin>,
+    pending: Pending,
+    next_id: AtomicU64,
+    _child: Child,
+}
+
+impl Worker {
+    #[throws]
+    pub fn spawn() -> Self {
+        let mut command = Command::new("node");
+        command.arg("./ffi.js").current_dir("./auth");
+        Self::spawn_with(command)?
+    }
+
+    /// Worker can be any command using the same protocol on stdin and stdout
+    #[throws]
+    pub fn spawn_with(mut command: Command) -> Self {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let stdin = child.stdin.take().ok_or_else(|| WorkerExited.build())?;
+        let stdout = child.stdout.take().ok_or_else(|| WorkerExited.build())?;
+
+        let pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        tokio::spawn(read_responses(stdout, pending.clone()));
+
+        Self {
+            stdin: AsyncMutex::new(stdin),
+            pending,
+            next_id: AtomicU64::new(0),
+            _child: child,
+        }
+    }
+
+    /// Sends the request and waits for its response
+    #[throws]
+    pub async fn call(&self, input: &(impl Serialize + Sync)) -> String {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut line = serde_json::to_string(&Request { id, input })?;
+        line.push('\n');
+
+        let (tx, rx) = oneshot::channel();
+        match self.pending.lock().unwrap().as_mut() {
+            Some(pending) => pending.insert(id, tx),
+            None => throw!(WorkerExited.build()),
+        };
+
+        if let Err(err) = self.write(line.as_bytes()).await {
+            if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+                pending.remove(&id);
+            }
+            // Stdin is closed once the worker exits
+            match err {
+                Error::Io { source } if source.kind() == ErrorKind::BrokenPipe => {
+                    throw!(WorkerExited.build())
+                }
+                err => throw!(err),
+            }
+        }
+
+        // Sender is dropped without response only when the worker exits
+        rx.await.map_err(|_| WorkerExited.build())??
+    }
+
+    #[throws]
+    async fn write(&self, line: &[u8]) {
+        let mut stdin = self.stdin.lock().await;
+        stdin.write_all(line).await?;
+        stdin.flush().await?;
+    }
+}
+
+async fn read_responses(stdout: ChildStdout, pending: Pending) {
+    let mut lines = BufReader::new(stdout).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let Ok(res) = serde_json::from_str::<Response>(&line) else {
+            // Response cannot be paired, so fail everything that is waiting
+            log::error!("Crypto worker printed garbage: {line}");
+            if let Some(pending) = pending.lock().unwrap().as_mut() {
+                for (_, tx) in pending.drain() {
+                    let _ = tx.send(WorkerGarbage { line: line.clone() }.fail());
+                }
+            }
+            continue;
+        };
+
+        let tx = pending.lock().unwrap().as_mut().and_then(|p| p.remove(&res.id));
+        match tx {
+            Some(tx) => {
+                let _ = tx.send(result(res));
+            }
+            None => log::warn!("Crypto worker responded to unknown request: {line}"),
+        }
+    }
+
+    log::error!("Crypto worker has exited");
+    pending.lock().unwrap().take();
+}
+
+fn result(res: Response) -> Result<String> {
+    match res {
+        Response {
+            result: Some(result),
+            ..
+        } => Ok(result),
+        Response { error, .. } => WorkerFailed {
+            msg: error.unwrap_or_default(),
+        }
+        .fail(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn worker(script: &str) -> Worker {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        Worker::spawn_with(command).unwrap()
+    }
+
+    #[tokio::test]
+    async fn garbage() {
+        let worker = worker("read line; echo garbage; read line");
+        let res = worker.call(&json!({})).await;
+        assert!(matches!(res, Err(Error::WorkerGarbage { line }) if line == "garbage"));
+    }
+
+    #[tokio::test]
+    async fn exited() {
+        let worker = worker("exit 0");
+        for _ in 0..2 {
+            assert!(matches!(worker.call(&json!({})).await, Err(Error::WorkerExited)));
+        }
+    }
+
+    #[tokio::test]
+    async fn out_of_order() {
+        let worker = worker(
+            r#"read a; read b; echo '{"id":1,"result":"01"}'; echo '{"id":0,"error":"failed"}'; read c"#,
+        );
+        let input = json!({});
+        let (first, second) = tokio::join!(worker.call(&input), worker.call(&input));
+        assert!(matches!(first, Err(Error::WorkerFailed { msg }) if msg == "failed"));
+        assert_eq!(second.unwrap(), "01");
+    }
+}
@@ -0,0 +1,237 @@
+//! Encrypted storage of the node key
+//! Uses the Web3 Secret Storage format, same as the Ethereum account keystores
+//!
+//! derived-key = scrypt(password, salt) or pbkdf2(password, salt)
+//! ciphertext = aes128ctr(derived-key[..16], iv, private-key)
+//! mac = keccak256(derived-key[16..32] || ciphertext)
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use aes::cipher::{KeyIvInit, StreamCipher};
+use fehler::{throw, throws};
+use hmac::Hmac;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use secp256k1::SecretKey;
+use sha2::Sha256;
+use sha3::{Digest, Keccak256};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+use crate::error::{KeystoreCipher, KeystoreMac, KeystoreParams, KeystoreVersion};
+use crate::utils::pub_key;
+use crate::Error;
+
+mod types;
+use types::*;
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+
+const VERSION: u32 = 3;
+const CIPHER: &str = "aes-128-ctr";
+
+/// Parameters used by geth for the standard keystore
+const SCRYPT_LOG_N: u8 = 18;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+/// Reads and decrypts the node key from the keystore file
+#[throws]
+pub fn import(path: &Path, password: &str) -> Zeroizing<[u8; 32]> {
+    decrypt(&fs::read_to_string(path)?, password)?
+}
+
+/// Encrypts the node key and stores it in a new keystore file
+#[throws]
+pub fn export(path: &Path, private_key: &[u8], password: &str) {
+    let mut opts = OpenOptions::new();
+    opts.write(true).create_new(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+
+    opts.open(path)?.write_all(encrypt(private_key, password)?.as_bytes())?;
+}
+
+/// Password is either given or prompted for
+#[throws]
+pub fn password(given: Option<&str>) -> Zeroizing<String> {
+    Zeroizing::new(match given {
+        Some(password) => password.to_string(),
+        None => rpassword::prompt_password("Keystore password: ")?,
+    })
+}
+
+#[throws]
+pub fn decrypt(json: &str, password: &str) -> Zeroizing<[u8; 32]> {
+    let keystore: Keystore = serde_json::from_str(json)?;
+    if keystore.version != VERSION {
+        throw!(KeystoreVersion {
+            version: keystore.version
+        }
+        .build());
+    }
+    let crypto = keystore.crypto;
+
+    if crypto.cipher != CIPHER {
+        throw!(KeystoreCipher {
+            cipher: crypto.cipher
+        }
+        .build());
+    }
+
+    let key = derive_key(&crypto.kdf, password)?;
+    let mut data = Zeroizing::new(hex::decode(crypto.ciphertext)?);
+
+    let mac = mac(&key, &data);
+    if !bool::from(mac.ct_eq(&hex::decode(crypto.mac)?)) {
+        throw!(KeystoreMac.build());
+    }
+
+    let iv = hex::decode(crypto.cipherparams.iv)?;
+    Aes128Ctr::new_from_slices(&key[..16], &iv)?.apply_keystream(&mut data);
+    Zeroizing::new(SecretKey::from_slice(&data)?.secret_bytes())
+}
+
+#[throws]
+pub fn encrypt(private_key: &[u8], password: &str) -> String {
+    let mut salt = [0; 32];
+    let mut iv = [0; 16];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut iv);
+
+    let kdf = Kdf::Scrypt(ScryptParams {
+        dklen: 32,
+        n: 1 << SCRYPT_LOG_N,
+        r: SCRYPT_R,
+        p: SCRYPT_P,
+        salt: hex::encode(salt),
+    });
+    let key = derive_key(&kdf, password)?;
+
+    let mut data = SecretKey::from_slice(private_key)?.secret_bytes();
+    Aes128Ctr::new_from_slices(&key[..16], &iv)?.apply_keystream(&mut data);
+
+    let keystore = Keystore {
+        crypto: CryptoJson {
+            cipher: CIPHER.to_string(),
+            cipherparams: CipherParams {
+                iv: hex::encode(iv),
+            },
+            ciphertext: hex::encode(data),
+            kdf,
+            mac: hex::encode(mac(&key, &data)),
+        },
+        id: uuid(),
+        version: VERSION,
+        address: Some(address(private_key)?),
+    };
+
+    serde_json::to_string(&keystore)?
+}
+
+#[throws]
+fn derive_key(kdf: &Kdf, password: &str) -> Zeroizing<Vec<u8>> {
+    match kdf {
+        Kdf::Scrypt(params) => {
+            if params.dklen < 32 || !params.n.is_power_of_two() {
+                throw!(KeystoreParams.build());
+            }
+
+            let log_n = params.n.trailing_zeros() as u8;
+            let scrypt_params = scrypt::Params::new(log_n, params.r, params.p)
+                .map_err(|_| KeystoreParams.build())?;
+
+            let mut key = Zeroizing::new(vec![0; params.dklen]);
+            scrypt::scrypt(
+                password.as_bytes(),
+                &hex::decode(&params.salt)?,
+                &scrypt_params,
+                &mut key,
+            )
+            .map_err(|_| KeystoreParams.build())?;
+            key
+        }
+        Kdf::Pbkdf2(params) => {
+            if params.dklen < 32 || params.prf != "hmac-sha256" {
+                throw!(KeystoreParams.build());
+            }
+
+            let mut key = Zeroizing::new(vec![0; params.dklen]);
+            let salt = hex::decode(&params.salt)?;
+            pbkdf2::pbkdf2::<Hmac<Sha256>>(password.as_bytes(), &salt, params.c, &mut key);
+            key
+        }
+    }
+}
+
+fn mac(key: &[u8], data: &[u8]) -> [u8; 32] {
+    Keccak256::new().chain_update(&key[16..32]).chain_update(data).finalize().into()
+}
+
+/// Ethereum address of the key, last 20 bytes of the public key hash
+#[throws]
+fn address(private_key: &[u8]) -> String {
+    hex::encode(&Keccak256::digest(pub_key(private_key)?)[12..])
+}
+
+/// Random UUID version 4
+fn uuid() -> String {
+    let mut id = [0; 16];
+    OsRng.fill_bytes(&mut id);
+    id[6] = (id[6] & 0x0f) | 0x40;
+    id[8] = (id[8] & 0x3f) | 0x80;
+
+    let id = hex::encode(id);
+    format!("{}-{}-{}-{}-{}", &id[..8], &id[8..12], &id[12..16], &id[16..20], &id[20..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRIVATE_KEY: &str = "7a28b5ba57c53603b0b07b56bba752f7784bf506fa95edc395f5cf6c7514fe9d";
+
+    #[test]
+    fn decrypts_pbkdf2_vector() {
+        let json = r#"{
+            "crypto": {
+                "cipher": "aes-128-ctr",
+                "cipherparams": { "iv": "6087dab2f9fdbbfaddc31a909735c1e6" },
+                "ciphertext": "5318b4d5bcd28de64ee5559e671353e16f075ecae9f99c7a79a38af5f869aa46",
+                "kdf": "pbkdf2",
+                "kdfparams": {
+                    "c": 262144,
+                    "dklen": 32,
+                    "prf": "hmac-sha256",
+                    "salt": "ae3cd4e7013836a3df6bd7241b12db061dbe2c6785853cce422d148a624ce0bd"
+                },
+                "mac": "517ead924a9d0dc3124507e3393d175ce3ff7c1e96529c6c555ce9e51205e9b2"
+            },
+            "id": "3198bc9c-6672-5ab3-d995-4942343ae5b6",
+            "version": 3
+        }"#;
+        let key = decrypt(json, "testpassword").unwrap();
+        assert_eq!(hex::encode(*key), PRIVATE_KEY);
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let json = encrypt(&hex::decode(PRIVATE_KEY).unwrap(), "password").unwrap();
+        let json = json.replace(r#""version":3"#, r#""version":1"#);
+        assert!(matches!(decrypt(&json, "password"), Err(Error::KeystoreVersion { version: 1 })));
+    }
+
+    #[test]
+    fn export_import() {
+        let path = std::env::temp_dir().join(format!("p2p-keystore-{}", uuid()));
+        let private_key = hex::decode(PRIVATE_KEY).unwrap();
+
+        export(&path, &private_key, "password").unwrap();
+        let key = import(&path, "password");
+        fs::remove_file(&path).unwrap();
+        assert_eq!(key.unwrap().as_slice(), private_key);
+    }
+}
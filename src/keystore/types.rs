@@ -0,0 +1,53 @@
+//! Web3 Secret Storage JSON format, version 3
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+pub struct Keystore {
+    // geth used capitalized name in older versions
+    #[serde(alias = "Crypto")]
+    pub crypto: CryptoJson,
+    pub id: String,
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CryptoJson {
+    pub cipher: String,
+    pub cipherparams: CipherParams,
+    pub ciphertext: String,
+    #[serde(flatten)]
+    pub kdf: Kdf,
+    pub mac: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CipherParams {
+    pub iv: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kdf", content = "kdfparams", rename_all = "lowercase")]
+pub enum Kdf {
+    Scrypt(ScryptParams),
+    Pbkdf2(Pbkdf2Params),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ScryptParams {
+    pub dklen: usize,
+    pub n: u64,
+    pub r: u32,
+    pub p: u32,
+    pub salt: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Pbkdf2Params {
+    pub dklen: usize,
+    pub c: u32,
+    pub prf: String,
+    pub salt: String,
+}
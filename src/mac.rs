@@ -2,23 +2,34 @@
 //! Needs to be synchronized with each message
 //! The encryption uses Aes256 Encoder
 
+use std::fmt;
+
 use aes::cipher::generic_array::GenericArray;
 use aes::cipher::{BlockEncrypt, KeyInit};
 use aes::Aes256Enc;
 use bytes::Bytes;
-use fehler::throws;
+use fehler::{throw, throws};
 use sha3::{Digest, Keccak256};
+use subtle::ConstantTimeEq;
 use typed_builder::TypedBuilder;
 
+use crate::error::{FrameMac, HeaderMac};
 use crate::utils::xor;
 use crate::Error;
 
-#[derive(TypedBuilder, Debug)]
+#[derive(TypedBuilder)]
 pub struct Mac {
     aes: Aes256Enc,
     hash: Keccak256,
 }
 
+/// Internal state is derived from the secret, so it is not printed
+impl fmt::Debug for Mac {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mac").finish_non_exhaustive()
+    }
+}
+
 // TODO comments
 /// Generate tags for RLPx message
 impl Mac {
@@ -54,6 +65,23 @@ impl Mac {
         self.digest()
     }
 
+    /// Verifies tag of the received header, state is updated the same way as
+    /// in `header_tag`
+    #[throws]
+    pub fn verify_header(&mut self, data: &[u8], tag: &[u8]) {
+        if !bool::from(self.header_tag(data).ct_eq(tag)) {
+            throw!(HeaderMac.build());
+        }
+    }
+
+    /// Verifies tag of the received frame data, must follow `verify_header`
+    #[throws]
+    pub fn verify_data(&mut self, data: &[u8], tag: &[u8]) {
+        if !bool::from(self.data_tag(data).ct_eq(tag)) {
+            throw!(FrameMac.build());
+        }
+    }
+
     fn digest(&mut self) -> Bytes {
         // TODO wrap around not being able to get digest without move
         let tmp_hash = self.hash.clone();
@@ -8,9 +8,12 @@ mod args;
 mod protocols;
 
 pub mod consts;
+pub mod crypto;
 pub mod error;
 pub mod ffi;
+pub mod keystore;
 pub mod mac;
+pub mod nodekey;
 pub mod rlpx;
 pub mod utils;
 
@@ -27,10 +30,35 @@ lazy_static! {
 async fn main() {
     env_logger::try_init()?;
 
-    let prot = Prot::new(&ARGS.address, ARGS.port);
+    // Same password is used when the keystore is both imported and exported
+    let password = match (&ARGS.keystore, &ARGS.export_keystore) {
+        (None, None) => None,
+        _ => Some(keystore::password(ARGS.keystore_password.as_deref())?),
+    };
 
-    prot.ping().await?;
+    let private_key = match (&ARGS.keystore, &password) {
+        (Some(path), Some(password)) => keystore::import(path, password)?,
+        _ => nodekey::load(ARGS.nodekey_hex.as_deref(), &ARGS.nodekey)?,
+    };
 
-    println!("------------------");
-    prot.auth().await?;
+    println!("Node ID: {}", hex::encode(utils::pub_key(&*private_key)?));
+
+    if let (Some(path), Some(password)) = (&ARGS.export_keystore, &password) {
+        keystore::export(path, &*private_key, password)?;
+        println!("Node key exported to: {}", path.display());
+        return;
+    }
+
+    let prot = Prot::new(&ARGS.address, ARGS.port, private_key);
+
+    if let Some(addr) = ARGS.listen {
+        prot.listen(addr).await?;
+    }
+    else if let Some(remote_id) = &ARGS.remote_id {
+        if !ARGS.skip_ping {
+            prot.ping().await?;
+            println!("------------------");
+        }
+        prot.auth(remote_id).await?;
+    }
 }
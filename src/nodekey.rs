@@ -0,0 +1,57 @@
+//! Static private key of the node, its public key is the node ID
+//! Either given as a hex string or stored in a geth-style `nodekey` file,
+//! which is generated on the first run
+
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+use fehler::{throw, throws};
+use rand::rngs::OsRng;
+use secp256k1::SecretKey;
+use zeroize::Zeroizing;
+
+use crate::Error;
+
+/// Hex string is given priority over the file
+#[throws]
+pub fn load(hex: Option<&str>, path: &Path) -> Zeroizing<[u8; 32]> {
+    match hex {
+        Some(hex) => from_hex(hex)?,
+        None => load_or_generate(path)?,
+    }
+}
+
+#[throws]
+pub fn from_hex(hex: &str) -> Zeroizing<[u8; 32]> {
+    let hex = hex.trim();
+    let key = Zeroizing::new(hex::decode(hex.strip_prefix("0x").unwrap_or(hex))?);
+    Zeroizing::new(SecretKey::from_slice(&key)?.secret_bytes())
+}
+
+/// Reads the key from the file or generates a new one and stores it there
+#[throws]
+pub fn load_or_generate(path: &Path) -> Zeroizing<[u8; 32]> {
+    match fs::read_to_string(path).map(Zeroizing::new) {
+        Ok(hex) => from_hex(&hex)?,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            let key = Zeroizing::new(SecretKey::new(&mut OsRng).secret_bytes());
+            save(path, &*key)?;
+            println!("Generated new node key: {}", path.display());
+            key
+        }
+        Err(err) => throw!(err),
+    }
+}
+
+/// Stores the key as hex without newline, same as geth does
+#[throws]
+pub fn save(path: &Path, key: &[u8]) {
+    let mut opts = OpenOptions::new();
+    opts.write(true).create_new(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+
+    opts.open(path)?.write_all(Zeroizing::new(hex::encode(key)).as_bytes())?;
+}
@@ -1,12 +1,26 @@
 //! P2P Handshake protocol implementation
 
+use std::future::Future;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
 
-use fehler::throws;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use bytes::{Bytes, BytesMut};
+use fehler::{throw, throws};
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::Framed;
+use zeroize::Zeroizing;
 
-use crate::consts::PRIVATE_KEY_HEX;
+use crate::crypto::CryptoBackend;
+use crate::error::{Disconnected, NodeIdMismatch, Result, Timeout, Truncated, UnexpectedMessage};
+use crate::rlpx::types::{
+    DisconnectReason, HelloMsg, Message, PeerInfo, ACK_LEGACY_SIZE, AUTH_LEGACY_SIZE,
+    DISCONNECT_ID, HELLO_ID,
+};
+use crate::rlpx::{session, Limits, RlpxCodec, Session, SharedCapabilities};
 use crate::{rlpx, Error, ARGS};
 
 /// An RLPx connection is established by creating a TCP connection and agreeing
@@ -29,45 +43,480 @@ use crate::{rlpx, Error, ARGS};
 /// 8. *initiator* receives and authenticates first encrypted frame
 /// 9. cryptographic handshake is complete if MAC of first encrypted frame
 ///     is valid on both sides
+///
+/// Every phase has its own deadline and the whole setup another one, the
+/// connection is closed once any of them passes
 #[throws]
-pub async fn auth(addr: &str, port: u16) {
+pub async fn auth(
+    addr: &str,
+    port: u16,
+    remote_id: &str,
+    private_key: &[u8],
+    crypto: Arc<dyn CryptoBackend>,
+) -> PeerInfo {
+    // Invalid limits fail before anything is sent
+    limits()?;
+    let setup = initiate(addr, port, remote_id, private_key, crypto);
+    let (framed, peer) = deadline("session setup", ARGS.setup_timeout, setup).await?;
+    keep_open(framed, &peer).await?;
+    peer
+}
+
+#[throws]
+async fn initiate(
+    addr: &str,
+    port: u16,
+    remote_id: &str,
+    private_key: &[u8],
+    crypto: Arc<dyn CryptoBackend>,
+) -> (Framed<TcpStream, RlpxCodec>, PeerInfo) {
     let full_addr = format!("{}:{}", addr, port);
 
     // TODO SSL?
-    let mut stream = TcpStream::connect(full_addr).await?;
+    let connect = TcpStream::connect(full_addr);
+    let mut stream = deadline("TCP connect", ARGS.connect_timeout, connect).await?;
     let addr = stream.local_addr()?;
     println!("Connecting to: {:#?}", addr);
 
-    let mut rlpx = rlpx::Rlpx::with_private_key(
-        &hex::decode(PRIVATE_KEY_HEX)?,
-        &hex::decode(&ARGS.remote_id)?,
-    )?;
+    let remote_id = hex::decode(remote_id)?;
+    let rlpx = rlpx::Rlpx::with_private_key(private_key, &remote_id, crypto)?;
 
     println!("Sending Auth message");
-    let auth_msg = rlpx.get_auth().await?;
+    let (rlpx, auth_msg) = if ARGS.legacy_handshake {
+        rlpx.get_auth_legacy().await?
+    }
+    else {
+        rlpx.get_auth().await?
+    };
     stream.write_all(&auth_msg).await?;
 
-    let mut buf = [0; 1024];
-    let msg = {
-        let l = stream.read(&mut buf).await?;
-        let l2 = stream.read(&mut buf[l..]).await?;
-        &buf[..l + l2]
-    };
+    let read_ack = read_ack(&mut stream, ARGS.legacy_handshake, limits()?);
+    let (ack_msg, buf) = deadline("ack", ARGS.ack_timeout, read_ack).await?;
     println!("Received Ack message");
 
-    let size = u16::from_be_bytes(msg[..2].try_into()?);
-    rlpx.parse_ack(&msg[..(size + 2) as usize]).await?;
+    let rlpx = rlpx.parse_ack(&ack_msg).await?;
+
+    // The remote Hello may have been received together with the Ack
+    let hello = rlpx.hello(addr.port(), &ARGS.capabilities);
+    let framed = rlpx.into_framed(stream, buf, limits()?);
+
+    let exchange = exchange_hello(framed, hello, &remote_id);
+    deadline("remote Hello", ARGS.hello_timeout, exchange).await?
+}
+
+/// Accepts incoming connections until an error occurs
+/// Every connection is handled in its own task, its errors are only printed
+pub async fn listen(
+    addr: SocketAddr,
+    private_key: &[u8],
+    crypto: Arc<dyn CryptoBackend>,
+) -> Result<()> {
+    // Invalid limits fail before anything is accepted
+    limits()?;
+    let listener = TcpListener::bind(addr).await?;
+    let port = listener.local_addr()?.port();
+    println!("Listening on: {}", listener.local_addr()?);
+
+    let private_key = Arc::new(Zeroizing::new(private_key.to_vec()));
+    loop {
+        let (stream, remote) = listener.accept().await?;
+        println!("Accepted connection from: {remote}");
+
+        let private_key = private_key.clone();
+        let crypto = crypto.clone();
+        tokio::spawn(async move {
+            if let Err(err) = accept(stream, port, &private_key, crypto).await {
+                println!("Connection from {remote} failed: {err}");
+            }
+        });
+    }
+}
+
+/// Recipient side of the handshake, see `auth`
+/// Auth has the same deadline as the Ack on the initiator side
+#[throws]
+pub async fn accept(
+    stream: TcpStream,
+    port: u16,
+    private_key: &[u8],
+    crypto: Arc<dyn CryptoBackend>,
+) -> PeerInfo {
+    let setup = respond(stream, port, private_key, crypto);
+    let (framed, peer) = deadline("session setup", ARGS.setup_timeout, setup).await?;
+    keep_open(framed, &peer).await?;
+    peer
+}
+
+#[throws]
+async fn respond(
+    mut stream: TcpStream,
+    port: u16,
+    private_key: &[u8],
+    crypto: Arc<dyn CryptoBackend>,
+) -> (Framed<TcpStream, RlpxCodec>, PeerInfo) {
+    let rlpx = rlpx::Rlpx::recipient(private_key, crypto)?;
+
+    // Legacy auth has a fixed size, EIP8 one is never shorter
+    let read_auth = async {
+        let mut buf = BytesMut::new();
+        read_at_least(&mut stream, &mut buf, AUTH_LEGACY_SIZE, "auth").await?;
+        let size = rlpx.auth_size(&buf[..AUTH_LEGACY_SIZE]).await?;
+        limits()?.check_handshake(size)?;
+        read_at_least(&mut stream, &mut buf, size, "auth").await?;
+        Ok::<_, Error>((buf.split_to(size), buf))
+    };
+    let (auth_msg, buf) = deadline("auth", ARGS.ack_timeout, read_auth).await?;
+    println!("Received Auth message");
+
+    let rlpx = rlpx.parse_auth(&auth_msg).await?;
+    let remote_id = Bytes::copy_from_slice(rlpx.remote_id());
+
+    println!("Sending Ack message");
+    let (rlpx, ack_msg) = rlpx.get_ack().await?;
+    stream.write_all(&ack_msg).await?;
+
+    let hello = rlpx.hello(port, &ARGS.capabilities);
+    let framed = rlpx.into_framed(stream, buf, limits()?);
+
+    let exchange = exchange_hello(framed, hello, &remote_id);
+    deadline("remote Hello", ARGS.hello_timeout, exchange).await?
+}
+
+/// Fails with `Timeout` naming the phase when the future does not finish in
+/// time, the future is dropped together with everything it owns
+#[throws]
+async fn deadline<F, T, E>(phase: &'static str, secs: u64, future: F) -> T
+where
+    F: Future<Output = std::result::Result<T, E>>,
+    Error: From<E>,
+{
+    match tokio::time::timeout(Duration::from_secs(secs), future).await {
+        Ok(res) => res?,
+        Err(_) => throw!(Timeout { phase, secs }.build()),
+    }
+}
+
+#[throws]
+fn limits() -> Limits {
+    let limits = Limits::builder()
+        .handshake_size(ARGS.max_handshake_size)
+        .frame_size(ARGS.max_frame_size)
+        .decompressed_size(ARGS.max_decompressed_size)
+        .buffer_size(ARGS.max_buffer_size)
+        .build();
+    limits.check()?;
+    limits
+}
+
+/// Reads the whole ack, bytes received after it are returned as well
+#[throws]
+async fn read_ack<T>(stream: &mut T, legacy: bool, limits: Limits) -> (BytesMut, BytesMut)
+where
+    T: AsyncRead + Unpin,
+{
+    let mut buf = BytesMut::new();
+    let size = if legacy {
+        ACK_LEGACY_SIZE
+    }
+    else {
+        read_at_least(stream, &mut buf, 2, "ack").await?;
+        u16::from_be_bytes(buf[..2].try_into()?) as usize + 2
+    };
+    limits.check_handshake(size)?;
+    read_at_least(stream, &mut buf, size, "ack").await?;
+    (buf.split_to(size), buf)
+}
+
+/// Reads until `buf` holds at least `len` bytes, anything read past them is
+/// kept in `buf` for the next message
+#[throws]
+async fn read_at_least<T>(stream: &mut T, buf: &mut BytesMut, len: usize, message: &'static str)
+where
+    T: AsyncRead + Unpin,
+{
+    buf.reserve(len.saturating_sub(buf.len()));
+    while buf.len() < len {
+        if stream.read_buf(buf).await? == 0 {
+            throw!(Truncated {
+                message,
+                expected: len,
+                received: buf.len()
+            }
+            .build());
+        }
+    }
+}
 
+/// Sends our Hello and checks the remote one, the node ID in it has to match
+/// the one used in the handshake
+#[throws]
+async fn exchange_hello<T>(
+    mut framed: Framed<T, RlpxCodec>,
+    hello: Message,
+    remote_id: &[u8],
+) -> (Framed<T, RlpxCodec>, PeerInfo)
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
     println!("Sending Hello message");
-    let msg = rlpx.get_hello(addr.port()).await?;
-    stream.write_all(&msg).await?;
+    framed.send(hello).await?;
+
+    let Some(msg) = framed.next().await.transpose()? else {
+        throw!(std::io::Error::from(ErrorKind::UnexpectedEof));
+    };
+    if msg.id == DISCONNECT_ID {
+        throw!(Disconnected {
+            reason: session::disconnect_reason(&msg.data)?
+        }
+        .build());
+    }
+    if msg.id != HELLO_ID {
+        throw!(UnexpectedMessage {
+            expected: HELLO_ID,
+            received: msg.id
+        }
+        .build());
+    }
+
+    let peer = PeerInfo::from(rlp::decode::<HelloMsg>(&msg.data)?);
+    if peer.node_id != remote_id {
+        framed.send(session::disconnect(DisconnectReason::UnexpectedIdentity)).await?;
+        throw!(NodeIdMismatch {
+            expected: hex::encode(remote_id),
+            received: hex::encode(&peer.node_id)
+        }
+        .build());
+    }
+
+    let caps = peer.capabilities.iter().map(ToString::to_string).collect::<Vec<_>>();
+    println!("Received Hello message from: {}", peer.client_id);
+    println!("Protocol version {}, capabilities: {}", peer.version, caps.join(", "));
 
-    let mut buf = [0; 1024];
-    let _l = stream.read(&mut buf).await?;
+    (framed, peer)
+}
+
+#[throws]
+async fn keep_open<T>(framed: Framed<T, RlpxCodec>, peer: &PeerInfo)
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let shared = SharedCapabilities::new(&ARGS.capabilities, &peer.capabilities);
+    for cap in shared.iter() {
+        println!("Shared capability {}/{} from ID {:#04x}", cap.name, cap.version, cap.offset);
+    }
 
     println!("Connected!");
     println!("Keeping the connection open for 5 sec...");
 
-    tokio::time::sleep(Duration::from_secs(5)).await;
+    let mut session = Session::new(framed);
+    let deadline = tokio::time::sleep(Duration::from_secs(5));
+    tokio::pin!(deadline);
+    loop {
+        let msg = tokio::select! {
+            msg = session.next() => msg,
+            _ = &mut deadline => {
+                match session.disconnect(DisconnectReason::ClientQuitting).await {
+                    // Both sides can quit at the same time
+                    Err(Error::Io { source }) if peer_closed(source.kind()) => {}
+                    res => res?,
+                }
+                break;
+            }
+        };
+
+        let msg = match msg {
+            Ok(Some(msg)) => msg,
+            Ok(None) => break,
+            Err(Error::Disconnected { reason }) => {
+                println!("Peer disconnected: {reason}");
+                break;
+            }
+            Err(err) => throw!(err),
+        };
+
+        match shared.route(msg.id) {
+            Some((cap, id)) => println!("Received {}/{} message {id:#04x}", cap.name, cap.version),
+            None => println!("Received message {:#04x}", msg.id),
+        }
+    }
     println!("Closed.");
 }
+
+fn peer_closed(kind: ErrorKind) -> bool {
+    matches!(kind, ErrorKind::NotConnected | ErrorKind::BrokenPipe | ErrorKind::ConnectionReset)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use rlp::RlpStream;
+    use tokio::io::{duplex, DuplexStream, ReadBuf};
+
+    use super::*;
+    use crate::crypto::Native;
+    use crate::utils::pub_key;
+
+    const LOCAL_KEY: [u8; 32] = [0x01; 32];
+    const REMOTE_KEY: [u8; 32] = [0x02; 32];
+
+    /// Both ends of a connection after a real handshake
+    async fn connected() -> (Framed<DuplexStream, RlpxCodec>, Framed<DuplexStream, RlpxCodec>) {
+        let remote_id = pub_key(&REMOTE_KEY).unwrap();
+        let initiator = rlpx::Rlpx::with_private_key(&LOCAL_KEY, &remote_id, Arc::new(Native));
+        let (initiator, auth) = initiator.unwrap().get_auth().await.unwrap();
+
+        let recipient = rlpx::Rlpx::recipient(&REMOTE_KEY, Arc::new(Native)).unwrap();
+        let recipient = recipient.parse_auth(&auth).await.unwrap();
+        let (recipient, ack) = recipient.get_ack().await.unwrap();
+        let initiator = initiator.parse_ack(&ack).await.unwrap();
+
+        let (local, remote) = duplex(4096);
+        (
+            initiator.into_framed(local, BytesMut::new(), Limits::default()),
+            recipient.into_framed(remote, BytesMut::new(), Limits::default()),
+        )
+    }
+
+    /// Hello of the key, optionally with additional list elements of a future
+    /// version
+    fn hello(key: &[u8; 32], additional: bool) -> Message {
+        let mut s = RlpStream::new_list(if additional { 7 } else { 5 });
+        s.append(&5_u32);
+        s.append(&"test");
+        s.begin_list(1).begin_list(2).append(&"eth").append(&66_u32);
+        s.append(&0_u16);
+        s.append(&pub_key(key).unwrap().as_slice());
+        if additional {
+            s.append(&"additional");
+            s.begin_list(2).append(&1_u8).append(&2_u8);
+        }
+        Message::builder().id(HELLO_ID).data(s.out().freeze()).build()
+    }
+
+    #[tokio::test]
+    async fn hello_additional_elements() {
+        let (local, mut remote) = connected().await;
+        remote.send(hello(&REMOTE_KEY, true)).await.unwrap();
+
+        let remote_id = pub_key(&REMOTE_KEY).unwrap();
+        let (_, peer) = exchange_hello(local, hello(&LOCAL_KEY, false), &remote_id).await.unwrap();
+        assert_eq!((peer.version, peer.client_id.as_str()), (5, "test"));
+        assert_eq!(peer.capabilities.iter().map(ToString::to_string).collect::<Vec<_>>(), [
+            "eth/66"
+        ]);
+        assert_eq!(peer.node_id, remote_id.as_slice());
+
+        assert_eq!(remote.next().await.unwrap().unwrap().id, HELLO_ID);
+    }
+
+    #[tokio::test]
+    async fn node_id_mismatch() {
+        let (local, mut remote) = connected().await;
+        remote.send(hello(&REMOTE_KEY, false)).await.unwrap();
+
+        let other_id = pub_key(&[0x03; 32]).unwrap();
+        let res = exchange_hello(local, hello(&LOCAL_KEY, false), &other_id).await;
+        assert!(matches!(res, Err(Error::NodeIdMismatch { .. })));
+
+        assert_eq!(remote.next().await.unwrap().unwrap().id, HELLO_ID);
+        let msg = remote.next().await.unwrap().unwrap();
+        assert_eq!(msg.id, DISCONNECT_ID);
+        let reason = session::disconnect_reason(&msg.data).unwrap();
+        assert_eq!(reason, DisconnectReason::UnexpectedIdentity);
+    }
+
+    /// Every read returns what fits of the next chunk, EOF once there are
+    /// none left
+    struct Chunks(VecDeque<Vec<u8>>);
+
+    impl AsyncRead for Chunks {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            if let Some(mut chunk) = self.0.pop_front() {
+                let rest = chunk.split_off(chunk.len().min(buf.remaining()));
+                buf.put_slice(&chunk);
+                if !rest.is_empty() {
+                    self.0.push_front(rest);
+                }
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Size prefix and 300 bytes of the ack followed by 10 bytes of the Hello
+    fn ack_and_hello() -> Vec<u8> {
+        [&300_u16.to_be_bytes()[..], &[0xaa; 300], &[0xbb; 10]].concat()
+    }
+
+    #[tokio::test]
+    async fn ack_in_chunks() {
+        let data = ack_and_hello();
+        for splits in [&[][..], &[1], &[150], &[1, 2], &[100, 302]] {
+            let mut chunks = VecDeque::new();
+            let mut start = 0;
+            for &end in splits.iter().chain([&data.len()]) {
+                chunks.push_back(data[start..end].to_vec());
+                start = end;
+            }
+
+            let mut stream = Chunks(chunks);
+            let (ack, rest) = read_ack(&mut stream, false, Limits::default()).await.unwrap();
+            assert_eq!(ack, data[..302], "{splits:?}");
+
+            // Hello bytes are either read into the buffer or still unread
+            let unread = stream.0.into_iter().flatten();
+            assert_eq!(rest.into_iter().chain(unread).collect::<Vec<_>>(), data[302..]);
+        }
+    }
+
+    /// Whatever fits into the buffer is read, bytes past the ack are kept
+    #[tokio::test]
+    async fn read_past_ack() {
+        let data = ack_and_hello();
+        let mut stream = Chunks(VecDeque::from([data[..100].to_vec(), data[100..].to_vec()]));
+        let mut buf = BytesMut::with_capacity(1024);
+
+        read_at_least(&mut stream, &mut buf, 302, "ack").await.unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[tokio::test]
+    async fn truncated_ack() {
+        let data = ack_and_hello();
+        for len in [1, 100] {
+            let mut stream = Chunks(VecDeque::from([data[..len].to_vec()]));
+            let res = read_ack(&mut stream, false, Limits::default()).await;
+            assert!(matches!(
+                res,
+                Err(Error::Truncated { message: "ack", received, .. }) if received == len
+            ));
+        }
+    }
+
+    /// Peer accepts the connection and never sends the ack
+    #[tokio::test]
+    async fn ack_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let _peer = listener.accept().await.unwrap();
+
+        tokio::time::pause();
+        let start = tokio::time::Instant::now();
+        let read_ack = read_ack(&mut stream, false, Limits::default());
+        let res = deadline("ack", 5, read_ack).await;
+        assert!(matches!(
+            res,
+            Err(Error::Timeout {
+                phase: "ack",
+                secs: 5
+            })
+        ));
+        assert!(start.elapsed() >= Duration::from_secs(5));
+    }
+}
@@ -1,6 +1,14 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
 use fehler::throws;
+use zeroize::Zeroizing;
 
-use crate::Error;
+use crate::args::Crypto;
+use crate::crypto::{CryptoBackend, Differential, Native};
+use crate::ffi::EncFfi;
+use crate::rlpx::types::PeerInfo;
+use crate::{Error, ARGS};
 
 mod auth;
 mod ping;
@@ -8,19 +16,26 @@ mod ping;
 pub struct Prot {
     addr: String,
     port: u16,
+    private_key: Zeroizing<[u8; 32]>,
 }
 
 impl Prot {
-    pub fn new(addr: &str, port: u16) -> Self {
+    pub fn new(addr: &str, port: u16, private_key: Zeroizing<[u8; 32]>) -> Self {
         Self {
             addr: addr.to_string(),
             port,
+            private_key,
         }
     }
 
     #[throws]
-    pub async fn auth(&self) {
-        auth::auth(&self.addr, self.port).await?;
+    pub async fn auth(&self, remote_id: &str) -> PeerInfo {
+        auth::auth(&self.addr, self.port, remote_id, &*self.private_key, crypto_backend()?).await?
+    }
+
+    #[throws]
+    pub async fn listen(&self, addr: SocketAddr) {
+        auth::listen(addr, &*self.private_key, crypto_backend()?).await?;
     }
 
     #[throws]
@@ -28,3 +43,15 @@ impl Prot {
         ping::ping(&self.addr, self.port).await?;
     }
 }
+
+#[throws]
+fn crypto_backend() -> Arc<dyn CryptoBackend> {
+    let backend: Arc<dyn CryptoBackend> = match ARGS.crypto {
+        Crypto::Native => Arc::new(Native),
+        Crypto::Node => Arc::new(EncFfi::new()?),
+        Crypto::Differential => {
+            Arc::new(Differential::new(Arc::new(EncFfi::new()?), Arc::new(Native)))
+        }
+    };
+    backend
+}
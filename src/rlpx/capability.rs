@@ -0,0 +1,192 @@
+//! Capabilities (subprotocols) running on top of the RLPx session
+//!
+//! Message IDs up to 0x10 are reserved for the base "p2p" protocol.
+//! Shared capabilities are the ones supported by both sides, in their highest
+//! common version. Sorted by name, each of them gets the next range of IDs.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use fehler::{throw, throws};
+
+use super::types::Protocol;
+use crate::error::CapabilityFormat;
+use crate::Error;
+
+/// Number of message IDs reserved for the base protocol
+pub const BASE_PROTOCOL_LENGTH: u64 = 0x10;
+
+/// Locally supported capability with the number of message IDs it uses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capability {
+    pub name: String,
+    pub version: u32,
+    pub length: u64,
+}
+
+impl Capability {
+    /// Number of messages of the well known capabilities
+    pub fn known_length(name: &str, version: u32) -> Option<u64> {
+        match (name, version) {
+            ("eth", 66..=68) => Some(17),
+            ("snap", 1) => Some(8),
+            _ => None,
+        }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        Protocol::builder().name(self.name.clone()).version(self.version).build()
+    }
+}
+
+/// Format is `name/version`, or `name/version/length` for unknown capabilities
+impl FromStr for Capability {
+    type Err = Error;
+
+    #[throws]
+    fn from_str(value: &str) -> Self {
+        let parts = value.split('/').collect::<Vec<_>>();
+        let parsed = match parts.as_slice() {
+            [name, version] => version.parse().ok().and_then(|version| {
+                Capability::known_length(name, version).map(|length| (name, version, length))
+            }),
+            [name, version, length] => version
+                .parse()
+                .ok()
+                .zip(length.parse().ok())
+                .map(|(version, length)| (name, version, length)),
+            _ => None,
+        };
+
+        match parsed {
+            Some((name, version, length)) if !name.is_empty() => Self {
+                name: name.to_string(),
+                version,
+                length,
+            },
+            _ => throw!(CapabilityFormat {
+                value: value.to_string()
+            }
+            .build()),
+        }
+    }
+}
+
+/// Capability negotiated with the peer, it uses the message IDs from `offset`
+/// to `offset + length`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SharedCapability {
+    pub name: String,
+    pub version: u32,
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SharedCapabilities {
+    caps: Vec<SharedCapability>,
+}
+
+impl SharedCapabilities {
+    pub fn new(local: &[Capability], remote: &[Protocol]) -> Self {
+        // Highest common version per name, BTreeMap keeps them sorted by name
+        let mut matched = BTreeMap::<&str, &Capability>::new();
+        for cap in local {
+            let common = remote.iter().any(|p| p.name == cap.name && p.version == cap.version);
+            let higher = matched.get(cap.name.as_str()).map_or(true, |m| m.version < cap.version);
+            if common && higher {
+                matched.insert(&cap.name, cap);
+            }
+        }
+
+        let mut offset = BASE_PROTOCOL_LENGTH;
+        let caps = matched
+            .into_values()
+            .map(|cap| {
+                let shared = SharedCapability {
+                    name: cap.name.clone(),
+                    version: cap.version,
+                    offset,
+                    length: cap.length,
+                };
+                offset += cap.length;
+                shared
+            })
+            .collect();
+
+        Self { caps }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SharedCapability> {
+        self.caps.iter()
+    }
+
+    /// Capability owning the received message ID and the ID relative to it
+    /// None for the base protocol and for IDs outside of all ranges
+    pub fn route(&self, id: u64) -> Option<(&SharedCapability, u64)> {
+        self.caps
+            .iter()
+            .find(|cap| (cap.offset..cap.offset + cap.length).contains(&id))
+            .map(|cap| (cap, id - cap.offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(caps: &[&str]) -> Vec<Capability> {
+        caps.iter().map(|cap| cap.parse().unwrap()).collect()
+    }
+
+    fn remote(caps: &[(&str, u32)]) -> Vec<Protocol> {
+        caps.iter()
+            .map(|(name, version)| {
+                Protocol::builder().name(name.to_string()).version(*version).build()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn highest_common_version_sorted_by_name() {
+        let shared = SharedCapabilities::new(
+            &local(&["snap/1", "eth/66", "eth/67", "eth/68", "les/4/24"]),
+            &remote(&[("eth", 66), ("eth", 67), ("snap", 1), ("wit", 0), ("les", 3)]),
+        );
+
+        let caps = shared.iter().map(|cap| (cap.name.as_str(), cap.version, cap.offset));
+        assert_eq!(caps.collect::<Vec<_>>(), [("eth", 67, 0x10), ("snap", 1, 0x21)]);
+    }
+
+    #[test]
+    fn nothing_in_common() {
+        let shared = SharedCapabilities::new(&local(&["eth/68"]), &remote(&[("eth", 67)]));
+        assert_eq!(shared.iter().count(), 0);
+        assert!(shared.route(BASE_PROTOCOL_LENGTH).is_none());
+    }
+
+    #[test]
+    fn route_boundaries() {
+        let shared = SharedCapabilities::new(
+            &local(&["eth/68", "snap/1"]),
+            &remote(&[("eth", 68), ("snap", 1)]),
+        );
+        let route = |id| shared.route(id).map(|(cap, id)| (cap.name.as_str(), id));
+
+        assert_eq!(route(0x0f), None);
+        assert_eq!(route(0x10), Some(("eth", 0x00)));
+        assert_eq!(route(0x20), Some(("eth", 0x10)));
+        assert_eq!(route(0x21), Some(("snap", 0x00)));
+        assert_eq!(route(0x28), Some(("snap", 0x07)));
+        assert_eq!(route(0x29), None);
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(local(&["eth/66"])[0].length, 17);
+        assert_eq!(local(&["les/4/24"])[0].length, 24);
+        for value in ["eth", "les/4", "/1/2", "eth/x", "eth/66/x/1"] {
+            assert!(value.parse::<Capability>().is_err(), "{value}");
+        }
+    }
+}
@@ -0,0 +1,184 @@
+//! Established RLPx session as a Stream and Sink of messages
+//!
+//! Messages following the Hello are compressed with snappy when both sides
+//! advertise protocol version 5 or newer, the Hello itself never is
+
+use bytes::{Bytes, BytesMut};
+use fehler::{throw, throws};
+use rlp::Rlp;
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::types::{Message, HELLO_ID};
+use super::{Established, Limits, Rlpx};
+use crate::error::DecompressedSize;
+use crate::Error;
+
+/// Lowest protocol version using snappy compression
+const SNAPPY_VERSION: u32 = 5;
+
+pub struct RlpxCodec {
+    rlpx: Rlpx<Established>,
+    limits: Limits,
+
+    // Protocol versions from the sent and received Hello
+    local_version: Option<u32>,
+    remote_version: Option<u32>,
+}
+
+impl RlpxCodec {
+    pub fn new(rlpx: Rlpx<Established>, limits: Limits) -> Self {
+        Self {
+            rlpx,
+            limits,
+            local_version: None,
+            remote_version: None,
+        }
+    }
+
+    fn snappy(&self) -> bool {
+        matches!(
+            (self.local_version, self.remote_version),
+            (Some(local), Some(remote)) if local >= SNAPPY_VERSION && remote >= SNAPPY_VERSION
+        )
+    }
+}
+
+impl Decoder for RlpxCodec {
+    type Error = Error;
+    type Item = Message;
+
+    #[throws]
+    fn decode(&mut self, src: &mut BytesMut) -> Option<Message> {
+        self.limits.check_buffer(src.len())?;
+
+        let mut msg = match self.rlpx.decode_frame(src, self.limits.frame_size)? {
+            Some(msg) => msg,
+            None => return None,
+        };
+
+        if msg.id == HELLO_ID {
+            self.remote_version = Some(hello_version(&msg.data)?);
+        }
+        else if self.snappy() {
+            msg.data = decompress(&msg.data, self.limits.decompressed_size)?;
+        }
+        Some(msg)
+    }
+}
+
+impl Encoder<Message> for RlpxCodec {
+    type Error = Error;
+
+    #[throws]
+    fn encode(&mut self, mut msg: Message, dst: &mut BytesMut) {
+        if msg.id == HELLO_ID {
+            self.local_version = Some(hello_version(&msg.data)?);
+        }
+        else if self.snappy() {
+            msg.data = snap::raw::Encoder::new().compress_vec(&msg.data)?.into();
+        }
+        self.rlpx.encode_frame(&msg, dst)?;
+    }
+}
+
+/// Protocol version is the first element of the Hello
+#[throws]
+fn hello_version(data: &[u8]) -> u32 {
+    Rlp::new(data).val_at(0)?
+}
+
+/// Size is checked before anything is allocated
+#[throws]
+fn decompress(data: &[u8], max: usize) -> Bytes {
+    let size = snap::raw::decompress_len(data)?;
+    if size > max {
+        throw!(DecompressedSize { size, max }.build());
+    }
+    snap::raw::Decoder::new().decompress_vec(data)?.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use rlp::RlpStream;
+
+    use super::*;
+    use crate::rlpx::frame::tests::established;
+    use crate::rlpx::frame::MAX_FRAME_SIZE;
+
+    fn hello(version: u32) -> Message {
+        let mut s = RlpStream::new_list(2);
+        s.append(&version).append(&"test");
+        Message::builder().id(HELLO_ID).data(s.out().freeze()).build()
+    }
+
+    fn message() -> Message {
+        Message::builder().id(0x10).data(Bytes::from(vec![0xab; 100])).build()
+    }
+
+    /// Codec and the raw frames of its peer after both Hellos are exchanged,
+    /// the Hellos are checked to be sent uncompressed
+    fn session(local: u32, remote: u32) -> (RlpxCodec, Rlpx<Established>) {
+        let mut codec = RlpxCodec::new(established(), Limits::default());
+        let mut peer = established();
+        let mut buf = BytesMut::new();
+
+        codec.encode(hello(local), &mut buf).unwrap();
+        let msg = peer.decode_frame(&mut buf, MAX_FRAME_SIZE).unwrap().unwrap();
+        assert_eq!(msg.data, hello(local).data);
+
+        peer.encode_frame(&hello(remote), &mut buf).unwrap();
+        let msg = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(msg.data, hello(remote).data);
+
+        (codec, peer)
+    }
+
+    /// Data of the message as sent on the wire and as decoded from it
+    fn exchange(local: u32, remote: u32) -> (Bytes, Bytes) {
+        let (mut codec, mut peer) = session(local, remote);
+        let mut buf = BytesMut::new();
+
+        codec.encode(message(), &mut buf).unwrap();
+        let sent = peer.decode_frame(&mut buf, MAX_FRAME_SIZE).unwrap().unwrap();
+
+        peer.encode_frame(&sent, &mut buf).unwrap();
+        let received = codec.decode(&mut buf).unwrap().unwrap();
+        (sent.data, received.data)
+    }
+
+    #[test]
+    fn hello_uncompressed() {
+        session(5, 5);
+    }
+
+    #[test]
+    fn compressed_after_hello() {
+        let (sent, received) = exchange(5, 5);
+        assert!(sent.len() < message().data.len());
+        assert_eq!(snap::raw::Decoder::new().decompress_vec(&sent).unwrap(), message().data);
+        assert_eq!(received, message().data);
+    }
+
+    #[test]
+    fn uncompressed_with_version_4() {
+        for (local, remote) in [(4, 5), (5, 4), (4, 4)] {
+            let (sent, received) = exchange(local, remote);
+            assert_eq!(sent, message().data);
+            assert_eq!(received, message().data);
+        }
+    }
+
+    /// Only the length header is present, so decompressing it would fail as
+    /// corrupt input instead
+    #[test]
+    fn decompressed_size() {
+        let res = decompress(&[0xff, 0xff, 0xff, 0x7f], 1024);
+        assert!(matches!(
+            res,
+            Err(Error::DecompressedSize {
+                size: 0xfff_ffff,
+                max: 1024
+            })
+        ));
+    }
+}
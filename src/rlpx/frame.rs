@@ -0,0 +1,309 @@
+//! Encoding and decoding of the RLPx frames
+//!
+//! frame = header-ciphertext || header-mac || frame-ciphertext || frame-mac
+//! header-ciphertext = aes(aes-secret, header)
+//! header = frame-size || header-data || header-padding
+//! header-data = [capability-id, context-id]
+//! frame-ciphertext = aes(aes-secret, frame-data || frame-padding)
+//! frame-data = msg-id || msg-data
+
+use aes::cipher::StreamCipher;
+use bytes::{BufMut, BytesMut};
+use fehler::{throw, throws};
+use rlp::Rlp;
+use snafu::ResultExt;
+
+use super::types::{CapHeader, Message};
+use super::{Established, Rlpx};
+use crate::error::{FrameHeader, FrameSize, UnsupportedFrame};
+use crate::utils::align_16;
+use crate::Error;
+
+/// Size of the header and of both MACs
+const BLOCK: usize = 16;
+
+/// Frame size is encoded as a 24-bit integer
+pub const MAX_FRAME_SIZE: usize = 0xff_ffff;
+
+/// Size of the whole frame on the wire with `size` bytes of frame data
+pub fn frame_len(size: usize) -> usize {
+    2 * BLOCK + align_16(size) + BLOCK
+}
+
+impl Rlpx<Established> {
+    /// Encrypts the message into a single frame appended to the buffer
+    /// Fails if the message does not fit into the frame
+    #[throws]
+    pub fn encode_frame(&mut self, msg: &Message, dst: &mut BytesMut) {
+        let mut data = BytesMut::new();
+        data.put(rlp::encode(&msg.id));
+        data.put(msg.data.as_ref());
+
+        let size = data.len();
+        if size > MAX_FRAME_SIZE {
+            throw!(FrameSize {
+                size,
+                max: MAX_FRAME_SIZE
+            }
+            .build());
+        }
+        data.resize(align_16(size), 0);
+
+        let mut header = {
+            let size = &(size as u32).to_be_bytes()[1..];
+            let cap = CapHeader::builder().cap_id(0).context_id(0).build();
+
+            let mut res = BytesMut::new();
+            res.put(size);
+            res.put(rlp::encode(&cap));
+            res.resize(BLOCK, 0);
+            res
+        };
+
+        let state = &mut self.state;
+        state.egress_aes.apply_keystream(&mut header);
+        state.egress_aes.apply_keystream(&mut data);
+        let header_tag = state.egress_mac.header_tag(&header);
+        let data_tag = state.egress_mac.data_tag(&data);
+
+        dst.reserve(header.len() + data.len() + 2 * BLOCK);
+        dst.put(header);
+        dst.put(header_tag);
+        dst.put(data);
+        dst.put(data_tag);
+    }
+
+    /// Decodes the next frame from the buffer into a message
+    /// Returns None until the whole frame is received, decoded bytes are
+    /// removed from the buffer
+    /// Frames over `max_size` are rejected as soon as their header is decoded
+    #[throws]
+    pub fn decode_frame(&mut self, buf: &mut BytesMut, max_size: usize) -> Option<Message> {
+        let state = &mut self.state;
+        let size = match state.ingress_frame_size {
+            Some(size) => size,
+            None => {
+                if buf.len() < 2 * BLOCK {
+                    return None;
+                }
+
+                let mut header = buf.split_to(BLOCK);
+                let tag = buf.split_to(BLOCK);
+                state.ingress_mac.verify_header(&header, &tag)?;
+                state.ingress_aes.apply_keystream(&mut header);
+
+                let size = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+                if size > max_size {
+                    throw!(FrameSize {
+                        size,
+                        max: max_size
+                    }
+                    .build());
+                }
+                let cap = decode_header_data(&header[3..]).context(FrameHeader)?;
+                check_header(&cap, size)?;
+                state.ingress_frame_size = Some(size);
+                size
+            }
+        };
+
+        if buf.len() < align_16(size) + BLOCK {
+            return None;
+        }
+        state.ingress_frame_size = None;
+
+        let mut data = buf.split_to(align_16(size));
+        let tag = buf.split_to(BLOCK);
+        state.ingress_mac.verify_data(&data, &tag)?;
+        state.ingress_aes.apply_keystream(&mut data);
+        data.truncate(size);
+
+        let id_len = Rlp::new(&data).payload_info()?.total();
+        let id = rlp::decode(data.get(..id_len).ok_or(rlp::DecoderError::RlpIsTooShort)?)?;
+        Some(Message::builder().id(id).data(data.freeze().slice(id_len..)).build())
+    }
+}
+
+/// The list is followed by the header padding, which must not be counted as
+/// its items
+#[throws(rlp::DecoderError)]
+fn decode_header_data(data: &[u8]) -> CapHeader {
+    let len = Rlp::new(data).payload_info()?.total();
+    rlp::decode(data.get(..len).ok_or(rlp::DecoderError::RlpIsTooShort)?)?
+}
+
+/// Chunked packets of the legacy spec are only accepted when they fit into a
+/// single frame, their messages are not reassembled
+#[throws]
+fn check_header(cap: &CapHeader, size: usize) {
+    match cap.total_size {
+        None if cap.context_id != 0 => throw!(UnsupportedFrame {
+            reason: "continuation of a chunked packet"
+        }
+        .build()),
+        Some(total_size) if total_size as usize != size => throw!(UnsupportedFrame {
+            reason: "packet chunked into multiple frames"
+        }
+        .build()),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Arc;
+
+    use aes::cipher::KeyIvInit;
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::crypto::Native;
+    use crate::mac::Mac;
+    use crate::rlpx::Aes256Ctr;
+    use crate::utils::pub_key;
+
+    /// Both directions share the secrets, so frames encoded by one session
+    /// decode in another
+    pub(crate) fn established() -> Rlpx<Established> {
+        let remote_id = pub_key(&[0x02; 32]).unwrap();
+        let rlpx = Rlpx::with_private_key(&[0x01; 32], &remote_id, Arc::new(Native)).unwrap();
+
+        let secret = [0x03; 32];
+        let mac = || {
+            let mut mac = Mac::with_secret(&secret).unwrap();
+            mac.update(b"seed");
+            mac
+        };
+        rlpx.into_state(Established {
+            egress_aes: Aes256Ctr::new_from_slices(&secret, &[0; 16]).unwrap(),
+            ingress_aes: Aes256Ctr::new_from_slices(&secret, &[0; 16]).unwrap(),
+            egress_mac: mac(),
+            ingress_mac: mac(),
+            ingress_frame_size: None,
+        })
+    }
+
+    fn encode(msg: &Message) -> BytesMut {
+        let mut buf = BytesMut::new();
+        established().encode_frame(msg, &mut buf).unwrap();
+        buf
+    }
+
+    fn message() -> Message {
+        Message::builder()
+            .id(0x10)
+            .data(Bytes::from_static(b"\xc3\x01\x02\x03"))
+            .build()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut buf = encode(&message());
+        let msg = established().decode_frame(&mut buf, MAX_FRAME_SIZE).unwrap().unwrap();
+        assert_eq!((msg.id, msg.data), (0x10, message().data));
+        assert!(buf.is_empty());
+    }
+
+    /// Frame size is a 24-bit integer, not just its lowest byte
+    #[test]
+    fn big_frame() {
+        let data = Bytes::from(vec![0xab; 5000]);
+        let msg = Message::builder().id(0x10).data(data.clone()).build();
+        let mut buf = encode(&msg);
+
+        let mut header = buf[..BLOCK].to_vec();
+        established().state.ingress_aes.apply_keystream(&mut header);
+        assert_eq!(header[..3], [0x00, 0x13, 0x89]);
+
+        let msg = established().decode_frame(&mut buf, MAX_FRAME_SIZE).unwrap().unwrap();
+        assert_eq!((msg.id, msg.data), (0x10, data));
+        assert!(buf.is_empty());
+    }
+
+    /// Message ID takes one byte of the frame
+    #[test]
+    fn too_big_frame() {
+        let data = Bytes::from(vec![0; MAX_FRAME_SIZE]);
+        let msg = Message::builder().id(0x10).data(data).build();
+        let res = established().encode_frame(&msg, &mut BytesMut::new());
+        assert!(matches!(
+            res,
+            Err(Error::FrameSize { size, max: MAX_FRAME_SIZE }) if size == MAX_FRAME_SIZE + 1
+        ));
+    }
+
+    #[test]
+    fn partial_frame() {
+        let mut rlpx = established();
+        let frame = encode(&message());
+        let mut buf = BytesMut::new();
+        for byte in &frame[..frame.len() - 1] {
+            buf.put_u8(*byte);
+            assert!(rlpx.decode_frame(&mut buf, MAX_FRAME_SIZE).unwrap().is_none());
+        }
+        buf.put_u8(frame[frame.len() - 1]);
+        assert!(rlpx.decode_frame(&mut buf, MAX_FRAME_SIZE).unwrap().is_some());
+    }
+
+    #[test]
+    fn tampered_header() {
+        let mut buf = encode(&message());
+        buf[0] ^= 1;
+        let res = established().decode_frame(&mut buf, MAX_FRAME_SIZE);
+        assert!(matches!(res, Err(Error::HeaderMac)));
+    }
+
+    #[test]
+    fn tampered_data() {
+        let mut buf = encode(&message());
+        buf[2 * BLOCK] ^= 1;
+        let res = established().decode_frame(&mut buf, MAX_FRAME_SIZE);
+        assert!(matches!(res, Err(Error::FrameMac)));
+    }
+
+    /// Header data as in the header, padded after the 3 byte frame size
+    fn header_data(data: &[u8]) -> [u8; BLOCK - 3] {
+        let mut buf = [0; BLOCK - 3];
+        buf[..data.len()].copy_from_slice(data);
+        buf
+    }
+
+    fn header(data: &[u8]) -> (u16, u16, Option<u32>) {
+        let cap = decode_header_data(&header_data(data)).unwrap();
+        (cap.cap_id, cap.context_id, cap.total_size)
+    }
+
+    #[test]
+    fn header_elements() {
+        assert_eq!(header(b"\xc1\x80"), (0, 0, None));
+        assert_eq!(header(b"\xc2\x80\x80"), (0, 0, None));
+        assert_eq!(header(b"\xc3\x80\x80\x20"), (0, 0, Some(0x20)));
+        assert_eq!(header(b"\xc4\x01\x02\x20\x05"), (1, 2, Some(0x20)));
+    }
+
+    #[test]
+    fn invalid_header() {
+        for data in [&b"\xc0"[..], b"\x80", b"\x01"] {
+            assert!(decode_header_data(&header_data(data)).is_err());
+        }
+    }
+
+    #[test]
+    fn header_check() {
+        let check = |data: &[u8], size| {
+            check_header(&decode_header_data(&header_data(data)).unwrap(), size)
+        };
+
+        assert!(check(b"\xc1\x80", 0x20).is_ok());
+        assert!(check(b"\xc2\x80\x80", 0x20).is_ok());
+        assert!(check(b"\xc3\x80\x80\x20", 0x20).is_ok());
+
+        // Continuation frame of a chunked packet
+        let res = check(b"\xc2\x80\x01", 0x20);
+        assert!(matches!(res, Err(Error::UnsupportedFrame { .. })));
+
+        // First frame of a packet chunked into multiple frames
+        let res = check(b"\xc3\x80\x80\x40", 0x20);
+        assert!(matches!(res, Err(Error::UnsupportedFrame { .. })));
+    }
+}
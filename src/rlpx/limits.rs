@@ -0,0 +1,106 @@
+//! Bounds on everything a peer can make this node read or allocate
+//! Handshake and frame sizes are checked as soon as they are known, before
+//! the bytes are received. The buffer is checked after the bytes are read
+//! into it, so it can go over the limit by a single read
+
+use fehler::{throw, throws};
+use typed_builder::TypedBuilder;
+
+use super::frame::frame_len;
+pub use super::frame::MAX_FRAME_SIZE;
+use crate::error::{BufferLimit, HandshakeLimit, InvalidBufferLimit};
+use crate::Error;
+
+/// EIP8 auth and ack are a few hundred bytes including the padding
+pub const MAX_HANDSHAKE_SIZE: usize = 2048;
+
+/// Geth does not accept bigger messages either
+pub const MAX_DECOMPRESSED_SIZE: usize = MAX_FRAME_SIZE;
+
+/// Room for the biggest frame and whatever is read after it
+pub const MAX_BUFFER_SIZE: usize = 2 * MAX_FRAME_SIZE;
+
+#[derive(Debug, Clone, Copy, TypedBuilder)]
+pub struct Limits {
+    /// Auth or ack including its size prefix
+    #[builder(default = MAX_HANDSHAKE_SIZE)]
+    pub handshake_size: usize,
+
+    /// Frame data as announced in the frame header
+    #[builder(default = MAX_FRAME_SIZE)]
+    pub frame_size: usize,
+
+    /// Message data after snappy decompression
+    #[builder(default = MAX_DECOMPRESSED_SIZE)]
+    pub decompressed_size: usize,
+
+    /// Bytes received from the connection and not decoded yet, the biggest
+    /// frame has to fit
+    #[builder(default = MAX_BUFFER_SIZE)]
+    pub buffer_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl Limits {
+    /// Fails when the biggest accepted frame does not fit into the buffer
+    #[throws]
+    pub fn check(&self) {
+        let frame = frame_len(self.frame_size);
+        if self.buffer_size < frame {
+            throw!(InvalidBufferLimit {
+                max: self.buffer_size,
+                frame
+            }
+            .build());
+        }
+    }
+
+    #[throws]
+    pub fn check_handshake(&self, size: usize) {
+        if size > self.handshake_size {
+            throw!(HandshakeLimit {
+                size,
+                max: self.handshake_size
+            }
+            .build());
+        }
+    }
+
+    #[throws]
+    pub fn check_buffer(&self, size: usize) {
+        if size > self.buffer_size {
+            throw!(BufferLimit {
+                size,
+                max: self.buffer_size
+            }
+            .build());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_fits_frame() {
+        assert!(Limits::default().check().is_ok());
+
+        let limits =
+            |buffer_size| Limits::builder().frame_size(1000).buffer_size(buffer_size).build();
+        assert!(limits(frame_len(1000)).check().is_ok());
+        let res = limits(frame_len(1000) - 1).check();
+        assert!(matches!(
+            res,
+            Err(Error::InvalidBufferLimit {
+                max: 1055,
+                frame: 1056
+            })
+        ));
+    }
+}
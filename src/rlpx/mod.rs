@@ -1,58 +1,174 @@
 //! Main file for the P2P Handshake protocol
-//! It follows the EIP8 ethereum format
-
-use core::slice::SlicePattern;
-
-use aes::cipher::{KeyIvInit, StreamCipher};
-use bytes::{BufMut, Bytes, BytesMut};
-use fehler::throws;
+//! It follows the EIP8 ethereum format, the legacy pre-EIP8 one is supported
+//! for older peers
+//!
+//! The handshake is a typestate, every step consumes the previous state:
+//! initiator `Rlpx<Initiated>` -> `Rlpx<AuthSent>` -> `Rlpx<Established>`
+//! recipient `Rlpx<Accepted>` -> `Rlpx<AuthReceived>` -> `Rlpx<Established>`
+
+use std::fmt;
+use std::sync::Arc;
+
+use aes::cipher::KeyIvInit;
+use bytes::{Bytes, BytesMut};
+use fehler::{throw, throws};
 use rand::rngs::OsRng;
 use rand::{thread_rng, Rng};
-use web3_hash_utils::keccak256;
+use sha3::{Digest, Keccak256};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Framed, FramedParts};
+use zeroize::Zeroizing;
 
-use crate::ffi::EncFfi;
+use crate::crypto::{ecies, CryptoBackend};
+use crate::error::{HandshakeField, HandshakeSize};
 use crate::mac::Mac;
-use crate::utils::{align_16, id2pk, nonce, pub_key, xor};
+use crate::utils::{id2pk, nonce, pub_key, xor};
 use crate::Error;
 
+mod capability;
+mod codec;
+mod frame;
+pub mod limits;
+pub mod session;
 pub mod types;
+pub use capability::{Capability, SharedCapabilities, SharedCapability};
+pub use codec::RlpxCodec;
+pub use limits::Limits;
+pub use session::Session;
 use types::*;
 
 type Aes256Ctr = ctr::Ctr64BE<aes::Aes256>;
-type Aes128Ctr = ctr::Ctr64BE<aes::Aes128>;
 
-pub struct Rlpx {
+pub struct Rlpx<S = Initiated> {
+    crypto: Arc<dyn CryptoBackend>,
     client_id: Bytes,
-    private_key: Bytes,
+    private_key: Zeroizing<[u8; 32]>,
     pub_key: [u8; 64],
-    eph_private_key: [u8; 32],
-    nonce: Bytes,
-
-    // Collected over time
-    // TODO use generic type to remove opts
-    init_msg: Option<Bytes>,
-    aes: Option<Aes256Ctr>,
-    mac: Option<Mac>,
+    eph_private_key: Zeroizing<[u8; 32]>,
+    nonce: Zeroizing<[u8; 32]>,
+    state: S,
+}
+
+/// Nothing was sent yet
+pub struct Initiated;
+
+/// Auth message is kept to seed the egress MAC
+pub struct AuthSent {
+    auth: Bytes,
+    legacy: bool,
+}
+
+/// Incoming connection, nothing was received yet
+pub struct Accepted;
+
+/// Auth message is kept to seed the ingress MAC
+/// Ack is sent in the same format as the received auth
+pub struct AuthReceived {
+    auth: Bytes,
+    rem_eph_pub_key: Bytes,
+    rem_nonce: Bytes,
+    legacy: bool,
+}
+
+/// Secrets are derived, frames can be sent and received
+pub struct Established {
+    egress_aes: Aes256Ctr,
+    ingress_aes: Aes256Ctr,
+    egress_mac: Mac,
+    ingress_mac: Mac,
+
+    // Size of the frame whose header was already decoded
+    ingress_frame_size: Option<usize>,
 }
 
-impl Rlpx {
+impl<S> Rlpx<S> {
     #[throws]
-    pub fn with_private_key(private_key: &[u8], client_id: &[u8]) -> Self {
+    fn new(private_key: &[u8], client_id: Bytes, crypto: Arc<dyn CryptoBackend>, state: S) -> Self {
         Self {
+            crypto,
             pub_key: pub_key(private_key)?,
-            private_key: Bytes::copy_from_slice(private_key),
-            client_id: Bytes::copy_from_slice(client_id),
-            eph_private_key: secp256k1::SecretKey::new(&mut OsRng).secret_bytes(),
-            nonce: nonce(),
-
-            // TODO remove options
-            // Use generic type with extended functionality to remove options
-            init_msg: None,
-            aes: None,
-            mac: None,
+            private_key: Zeroizing::new(private_key.try_into()?),
+            client_id,
+            eph_private_key: Zeroizing::new(secp256k1::SecretKey::new(&mut OsRng).secret_bytes()),
+            nonce: Zeroizing::new(nonce()),
+            state,
         }
     }
 
+    /// Node ID of the remote peer
+    pub fn remote_id(&self) -> &[u8] {
+        &self.client_id
+    }
+
+    /// Shared secrets
+    /// static-shared-secret = ecdh.agree(privkey, remote-pubk)
+    /// ephemeral-key = ecdh.agree(ephemeral-privkey, remote-ephemeral-pubk)
+    /// shared-secret = keccak256(ephemeral-key
+    ///     || keccak256(nonce || initiator-nonce))
+    /// aes-secret = keccak256(ephemeral-key || shared-secret)
+    /// mac-secret = keccak256(ephemeral-key || aes-secret)
+    ///
+    /// `sent` and `received` are the auth and ack messages as on the wire
+    #[throws]
+    async fn establish(
+        self,
+        rem_eph_pub_key: &[u8],
+        rem_nonce: &[u8],
+        h_nonce: &[u8],
+        sent: &[u8],
+        received: &[u8],
+    ) -> Rlpx<Established> {
+        let eph_shared_secret = self.crypto.ecdh(&*self.eph_private_key, rem_eph_pub_key).await?;
+
+        let shared_secret = keccak256_concat(&eph_shared_secret, h_nonce);
+        let aes_secret = keccak256_concat(&eph_shared_secret, &*shared_secret);
+        let mac_secret = keccak256_concat(&eph_shared_secret, &*aes_secret);
+
+        // Both directions use the same key, each with its own keystream
+        let iv = [0x0; 16];
+
+        // egress-mac = keccak256.init((mac-secret ^ remote-nonce) || sent)
+        let mut egress_mac = Mac::with_secret(&*mac_secret)?;
+        egress_mac.update(&Zeroizing::new(xor(&*mac_secret, rem_nonce)));
+        egress_mac.update(sent);
+
+        // ingress-mac = keccak256.init((mac-secret ^ nonce) || received)
+        let mut ingress_mac = Mac::with_secret(&*mac_secret)?;
+        ingress_mac.update(&Zeroizing::new(xor(&*mac_secret, &*self.nonce)));
+        ingress_mac.update(received);
+
+        self.into_state(Established {
+            egress_aes: Aes256Ctr::new_from_slices(&*aes_secret, &iv)?,
+            ingress_aes: Aes256Ctr::new_from_slices(&*aes_secret, &iv)?,
+            egress_mac,
+            ingress_mac,
+            ingress_frame_size: None,
+        })
+    }
+
+    fn into_state<T>(self, state: T) -> Rlpx<T> {
+        Rlpx {
+            crypto: self.crypto,
+            client_id: self.client_id,
+            private_key: self.private_key,
+            pub_key: self.pub_key,
+            eph_private_key: self.eph_private_key,
+            nonce: self.nonce,
+            state,
+        }
+    }
+}
+
+impl Rlpx<Initiated> {
+    #[throws]
+    pub fn with_private_key(
+        private_key: &[u8],
+        client_id: &[u8],
+        crypto: Arc<dyn CryptoBackend>,
+    ) -> Self {
+        Self::new(private_key, Bytes::copy_from_slice(client_id), crypto, Initiated)?
+    }
+
     /// **Authorization message format:**
     /// auth = auth-size || enc-auth-body
     /// auth-size = size of enc-auth-body, encoded as a big-endian 16-bit
@@ -61,19 +177,14 @@ impl Rlpx {
     /// enc-auth-body = ecies.encrypt(recipient-pubk, auth-body || auth-padding,
     /// auth-size) auth-padding = arbitrary data
     #[throws]
-    pub async fn get_auth(&mut self) -> Bytes {
-        let ecdhx = {
-            let e = EncFfi::ecdhx(&self.private_key, &id2pk(&self.client_id)).await?;
-            xor(&e, &self.nonce)
-        };
-
-        let sig = EncFfi::ecdsa_sign(&self.eph_private_key, &ecdhx).await?;
+    pub async fn get_auth(self) -> (Rlpx<AuthSent>, Bytes) {
+        let sig = self.auth_sig().await?;
 
         let msg = {
             let auth_msg = AuthMsg::builder()
                 .sig(sig)
                 .pub_key(Bytes::copy_from_slice(self.pub_key.as_slice()))
-                .nonce(self.nonce.clone())
+                .nonce(Bytes::copy_from_slice(&*self.nonce))
                 .version(4)
                 .build();
             let mut msg = rlp::encode(&auth_msg);
@@ -81,17 +192,52 @@ impl Rlpx {
             msg.freeze()
         };
 
-        // TODO twice
-        let ecies_overhead = 113;
-        let mac_data = ((msg.len() + ecies_overhead) as u16).to_be_bytes();
+        let mac_data = ((msg.len() + ecies::OVERHEAD) as u16).to_be_bytes();
 
-        let enc = EncFfi::tagged_kdf(&msg, &id2pk(&self.client_id), &mac_data).await?;
+        let enc = self.crypto.ecies_encrypt(&id2pk(&self.client_id), &msg, &mac_data).await?;
 
         let msg = BytesMut::from_iter(mac_data.iter().chain(enc.iter())).freeze();
-        self.init_msg = Some(msg.clone());
-        msg
+        let auth = msg.clone();
+        (
+            self.into_state(AuthSent {
+                auth,
+                legacy: false,
+            }),
+            msg,
+        )
     }
 
+    /// **Legacy authorization message format (pre-EIP8):**
+    /// auth = ecies.encrypt(recipient-pubk, auth-body)
+    /// auth-body = sig || keccak256(ephemeral-pubk) || initiator-pubk ||
+    /// initiator-nonce || 0x0
+    ///
+    /// The message has no size prefix, it is always `AUTH_LEGACY_SIZE` long
+    #[throws]
+    pub async fn get_auth_legacy(self) -> (Rlpx<AuthSent>, Bytes) {
+        let sig = self.auth_sig().await?;
+        let eph_pub_key_hash = Keccak256::digest(pub_key(&*self.eph_private_key)?);
+
+        let body = [&sig[..], &eph_pub_key_hash, &self.pub_key, &*self.nonce, &[0x0]].concat();
+        let msg = self.crypto.ecies_encrypt(&id2pk(&self.client_id), &body, &[]).await?;
+
+        let auth = msg.clone();
+        (self.into_state(AuthSent { auth, legacy: true }), msg)
+    }
+
+    /// sig = ecdsa.sign(ephemeral-privkey, static-shared-secret ^ nonce)
+    #[throws]
+    async fn auth_sig(&self) -> Bytes {
+        let ecdhx = {
+            let e = self.crypto.ecdh(&*self.private_key, &id2pk(&self.client_id)).await?;
+            Zeroizing::new(xor(&e, &*self.nonce))
+        };
+
+        self.crypto.sign(&*self.eph_private_key, &ecdhx).await?
+    }
+}
+
+impl Rlpx<AuthSent> {
     /// **Acknowledge message format:**
     /// ack = ack-size || enc-ack-body
     /// ack-size = size of enc-ack-body, encoded as a big-endian 16-bit integer
@@ -99,84 +245,194 @@ impl Rlpx {
     /// ack-body = [recipient-ephemeral-pubk, recipient-nonce, ack-vsn, ...]
     /// enc-ack-body = ecies.encrypt(initiator-pubk, ack-body || ack-padding,
     /// ack-size) ack-padding = arbitrary data
+    ///
+    /// **Legacy acknowledge message format (pre-EIP8):**
+    /// ack = ecies.encrypt(initiator-pubk, recipient-ephemeral-pubk ||
+    /// recipient-nonce || 0x0)
+    ///
+    /// Legacy ack is expected only for the legacy auth
     #[throws]
-    pub async fn parse_ack(&mut self, msg: &[u8]) {
-        let (aes_secret, mac_secret, rem_nonce) = {
-            let (rem_nonce, eph_shared_secret) = {
-                let output = EncFfi::concat_kdf(msg, &self.private_key).await?;
-
-                // decrypt data
-                let output = {
-                    let mut msg = msg[65 + 2..].to_vec(); // public key + len
-                    let iv: [u8; 16] = msg[..16].try_into()?;
-                    let msg2 = &mut msg[16..];
-                    let key: [u8; 16] = output.as_slice()[..16].try_into()?;
-                    let mut aes = Aes128Ctr::new(&key.into(), &iv.into());
-                    aes.apply_keystream(msg2);
-                    Bytes::copy_from_slice(msg2)
-                };
-
-                // TODO once in a while fails
-                // Ideally the method should return decode error so we could try with different
-                // data
-                let a: Vec<Bytes> = rlp::decode_list(&output);
-                let rem_eph_pub_key = id2pk(&a[0]);
-                let rem_nonce = Bytes::copy_from_slice(&a[1]);
-
-                let eph_shared_secret =
-                    EncFfi::ecdhx(self.eph_private_key.as_slice(), &rem_eph_pub_key).await?;
-                (rem_nonce, eph_shared_secret)
-            };
-
-            let h_nonce =
-                keccak256(BytesMut::from_iter(rem_nonce.iter().chain(self.nonce.iter())).freeze());
-
-            let shared_secret = keccak256(
-                BytesMut::from_iter(eph_shared_secret.iter().chain(h_nonce.iter())).freeze(),
-            );
-
-            let aes_secret = keccak256(
-                BytesMut::from_iter(eph_shared_secret.iter().chain(shared_secret.iter())).freeze(),
-            );
-
-            let mac_secret = keccak256(
-                BytesMut::from_iter(eph_shared_secret.iter().chain(aes_secret.iter())).freeze(),
-            );
-
-            (aes_secret, mac_secret, rem_nonce)
+    pub async fn parse_ack(self, msg: &[u8]) -> Rlpx<Established> {
+        let ack: AckMsg = if self.state.legacy {
+            let output = self.crypto.ecies_decrypt(&*self.private_key, msg, &[]).await?;
+            if output.len() != ACK_LEGACY_BODY_SIZE {
+                throw!(HandshakeField { field: "ack body" }.build());
+            }
+            AckMsg::builder()
+                .pub_key(output.slice(..64))
+                .nonce(output.slice(64..96))
+                .version(output[96].into())
+                .build()
+        }
+        else {
+            let (ack_size, enc) = split_size(msg)?;
+            let output = self.crypto.ecies_decrypt(&*self.private_key, enc, ack_size).await?;
+            rlp::decode(&output)?
         };
 
-        // Shared secrets
-        // static-shared-secret = ecdh.agree(privkey, remote-pubk)
-        // ephemeral-key = ecdh.agree(ephemeral-privkey, remote-ephemeral-pubk)
-        // shared-secret = keccak256(ephemeral-key
-        //     || keccak256(nonce || initiator-nonce))
-        // aes-secret = keccak256(ephemeral-key || shared-secret)
-        // mac-secret = keccak256(ephemeral-key || aes-secret)
+        check_sizes([
+            ("ephemeral public key", &ack.pub_key[..], 64),
+            ("nonce", &ack.nonce[..], 32),
+        ])?;
 
-        let iv = [0x0; 16];
-        self.aes = Some(Aes256Ctr::new(&aes_secret.into(), &iv.into()));
+        // keccak256(recipient-nonce || initiator-nonce)
+        let h_nonce = keccak256_concat(&ack.nonce, &*self.nonce);
+        let auth = self.state.auth.clone();
+        self.establish(&id2pk(&ack.pub_key), &ack.nonce, &*h_nonce, &auth, msg).await?
+    }
+}
+
+impl Rlpx<Accepted> {
+    /// Remote node ID is not known until the auth message is received
+    #[throws]
+    pub fn recipient(private_key: &[u8], crypto: Arc<dyn CryptoBackend>) -> Self {
+        Self::new(private_key, Bytes::new(), crypto, Accepted)?
+    }
+
+    /// Size of the whole auth message from its first `AUTH_LEGACY_SIZE` bytes
+    /// Legacy format has no size prefix so it is tried first, the same way geth
+    /// does, then the bytes are taken as the EIP8 one
+    #[throws]
+    pub async fn auth_size(&self, head: &[u8]) -> usize {
+        if self.decrypt_legacy(head).await.is_some() {
+            return AUTH_LEGACY_SIZE;
+        }
+
+        if head.len() < AUTH_LEGACY_SIZE {
+            throw!(HandshakeSize {
+                size: head.len(),
+                min: AUTH_LEGACY_SIZE
+            }
+            .build());
+        }
+
+        let size = u16::from_be_bytes(head[..2].try_into()?) as usize + 2;
+        if size < AUTH_LEGACY_SIZE {
+            throw!(HandshakeSize {
+                size,
+                min: AUTH_LEGACY_SIZE
+            }
+            .build());
+        }
+        size
+    }
 
-        let mac_update = {
-            let mut a = BytesMut::new();
-            a.put(xor(&mac_secret, &rem_nonce).as_slice());
+    /// Decrypts the auth message described in `get_auth` or `get_auth_legacy`
+    /// Remote ephemeral key is recovered from the signature of
+    /// static-shared-secret ^ initiator-nonce
+    #[throws]
+    pub async fn parse_auth(mut self, msg: &[u8]) -> Rlpx<AuthReceived> {
+        let legacy = match msg.len() {
+            AUTH_LEGACY_SIZE => self.decrypt_legacy(msg).await,
+            _ => None,
+        };
 
-            if let Some(msg) = &self.init_msg {
-                a.put(msg.as_slice());
+        let (auth, eph_pub_key_hash) = match &legacy {
+            Some(output) => {
+                if output.len() != AUTH_LEGACY_BODY_SIZE {
+                    throw!(HandshakeField { field: "auth body" }.build());
+                }
+                let auth = AuthMsg::builder()
+                    .sig(output.slice(..65))
+                    .pub_key(output.slice(97..161))
+                    .nonce(output.slice(161..193))
+                    .version(output[193].into())
+                    .build();
+                (auth, Some(output.slice(65..97)))
             }
-            else {
-                panic!("NO INIT MSG!"); // TODO error
+            None => {
+                let (auth_size, enc) = split_size(msg)?;
+                let output = self.crypto.ecies_decrypt(&*self.private_key, enc, auth_size).await?;
+                (rlp::decode(&output)?, None)
             }
-            a.freeze()
         };
 
-        self.mac = Some({
-            let mut mac = Mac::with_secret(&mac_secret)?;
-            mac.update(&mac_update);
-            mac
-        });
+        check_sizes([
+            ("signature", &auth.sig[..], 65),
+            ("public key", &auth.pub_key[..], 64),
+            ("nonce", &auth.nonce[..], 32),
+        ])?;
+
+        let ecdhx = {
+            let e = self.crypto.ecdh(&*self.private_key, &id2pk(&auth.pub_key)).await?;
+            Zeroizing::new(xor(&e, &auth.nonce))
+        };
+        let rem_eph_pub_key = self.crypto.recover(&auth.sig, &ecdhx).await?;
+
+        if let Some(hash) = eph_pub_key_hash {
+            if Keccak256::digest(&rem_eph_pub_key).as_slice() != hash {
+                throw!(HandshakeField {
+                    field: "ephemeral public key hash"
+                }
+                .build());
+            }
+        }
+
+        self.client_id = auth.pub_key;
+        self.into_state(AuthReceived {
+            auth: Bytes::copy_from_slice(msg),
+            rem_eph_pub_key,
+            rem_nonce: auth.nonce,
+            legacy: legacy.is_some(),
+        })
     }
 
+    /// Legacy auth is encrypted without any shared MAC data
+    async fn decrypt_legacy(&self, msg: &[u8]) -> Option<Bytes> {
+        self.crypto.ecies_decrypt(&*self.private_key, msg, &[]).await.ok()
+    }
+}
+
+impl Rlpx<AuthReceived> {
+    /// Ack message format is described in `parse_ack`
+    #[throws]
+    pub async fn get_ack(self) -> (Rlpx<Established>, Bytes) {
+        let eph_pub_key = pub_key(&*self.eph_private_key)?;
+
+        let msg = if self.state.legacy {
+            let body = [&eph_pub_key[..], &*self.nonce, &[0x0]].concat();
+            self.crypto.ecies_encrypt(&id2pk(&self.client_id), &body, &[]).await?
+        }
+        else {
+            self.get_ack_eip8(&eph_pub_key).await?
+        };
+
+        // keccak256(recipient-nonce || initiator-nonce)
+        let h_nonce = keccak256_concat(&*self.nonce, &self.state.rem_nonce);
+        let AuthReceived {
+            auth,
+            rem_eph_pub_key,
+            rem_nonce,
+            ..
+        } = &self.state;
+        let (auth, rem_eph_pub_key, rem_nonce) =
+            (auth.clone(), id2pk(rem_eph_pub_key), rem_nonce.clone());
+
+        let rlpx = self.establish(&rem_eph_pub_key, &rem_nonce, &*h_nonce, &msg, &auth).await?;
+        (rlpx, msg)
+    }
+
+    #[throws]
+    async fn get_ack_eip8(&self, eph_pub_key: &[u8]) -> Bytes {
+        let msg = {
+            let ack_msg = AckMsg::builder()
+                .pub_key(Bytes::copy_from_slice(eph_pub_key))
+                .nonce(Bytes::copy_from_slice(&*self.nonce))
+                .version(4)
+                .build();
+            let mut msg = rlp::encode(&ack_msg);
+            msg.resize(msg.len() + thread_rng().gen_range(100..=250), 0);
+            msg.freeze()
+        };
+
+        let mac_data = ((msg.len() + ecies::OVERHEAD) as u16).to_be_bytes();
+
+        let enc = self.crypto.ecies_encrypt(&id2pk(&self.client_id), &msg, &mac_data).await?;
+        BytesMut::from_iter(mac_data.iter().chain(enc.iter())).freeze()
+    }
+}
+
+impl Rlpx<Established> {
     /// **Hello message format**
     /// Frame data
     /// frame-data = msg-id || msg-data
@@ -184,7 +440,8 @@ impl Rlpx {
     ///
     /// Hello (0x00)
     ///
-    /// [protocolVersion: P, clientId: B, capabilities, listenPort: P, nodeKey: B_64, ...]
+    /// [protocolVersion: P, clientId: B, capabilities, listenPort: P, nodeKey:
+    /// B_64, ...]
     ///
     /// First packet sent over the connection, and sent once by both sides.
     /// No other messages may be sent until a Hello is received.
@@ -199,67 +456,349 @@ impl Rlpx {
     /// **listenPort** specifies the port that the client is listening on
     ///     (on the interface that the present connection traverses).
     ///     If 0 it indicates the client is not listening.
-    /// **nodeId** is the secp256k1 public key corresponding to the node's private key.
-    #[throws]
-    pub async fn get_hello(&mut self, port: u16) -> Bytes {
-        let mut mac_data = {
-            let prot = Protocol::builder().name("eth".to_string()).t(66).build();
-
-            let hello = HelloMsg::builder()
-                .version(5)
-                .name("Michal Režňák".to_string())
-                .protocols(vec![prot])
-                .port(port)
-                .pub_key(Bytes::copy_from_slice(self.pub_key.as_slice()))
-                .build();
+    /// **nodeId** is the secp256k1 public key corresponding to the node's
+    /// private key.
+    pub fn hello(&self, port: u16, capabilities: &[Capability]) -> Message {
+        let hello = HelloMsg::builder()
+            .version(PROTOCOL_VERSION)
+            .name("Michal Režňák".to_string())
+            .protocols(capabilities.iter().map(Capability::protocol).collect())
+            .port(port)
+            .pub_key(Bytes::copy_from_slice(self.pub_key.as_slice()))
+            .build();
+
+        Message::builder().id(HELLO_ID).data(rlp::encode(&hello).freeze()).build()
+    }
 
-            let hello = rlp::encode(&hello);
-            let hello_prefix = rlp::encode(&HelloPrefixMsg::builder().zero(0).build());
+    /// Wraps the stream into a Stream and Sink of messages
+    /// Must be called after the secrets are derived from the Ack, `read_buf`
+    /// holds the bytes received after the Ack
+    pub fn into_framed<T>(
+        self,
+        stream: T,
+        read_buf: BytesMut,
+        limits: Limits,
+    ) -> Framed<T, RlpxCodec>
+    where
+        T: AsyncRead + AsyncWrite,
+    {
+        let mut parts = FramedParts::new(stream, RlpxCodec::new(self, limits));
+        parts.read_buf = read_buf;
+        Framed::from_parts(parts)
+    }
+}
 
-            let mut res = BytesMut::new();
-            res.put(hello_prefix);
-            res.put(hello);
+/// Secrets are only shown as redacted
+impl<S> fmt::Debug for Rlpx<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Rlpx")
+            .field("crypto", &self.crypto.name())
+            .field("client_id", &hex::encode(&self.client_id))
+            .field("pub_key", &hex::encode(self.pub_key))
+            .finish_non_exhaustive()
+    }
+}
 
-            // Align to multiple of 16
-            res.resize(align_16(res.len()), 0);
-            res
-        };
+/// EIP8 messages start with the 2-byte size of the rest, used as the ECIES
+/// shared MAC data
+#[throws]
+fn split_size(msg: &[u8]) -> (&[u8], &[u8]) {
+    if msg.len() < 2 {
+        throw!(HandshakeSize {
+            size: msg.len(),
+            min: 2_usize
+        }
+        .build());
+    }
+    msg.split_at(2)
+}
+
+/// Decoded auth and ack fields are only checked by their size
+#[throws]
+fn check_sizes<const N: usize>(fields: [(&'static str, &[u8], usize); N]) {
+    for (field, value, len) in fields {
+        if value.len() != len {
+            throw!(HandshakeField { field }.build());
+        }
+    }
+}
+
+/// keccak256(a || b) without copying the inputs into a temporary buffer
+fn keccak256_concat(a: &[u8], b: &[u8]) -> Zeroizing<[u8; 32]> {
+    Zeroizing::new(Keccak256::new().chain_update(a).chain_update(b).finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use aes::cipher::StreamCipher;
+
+    use super::*;
+    use crate::crypto::Native;
+
+    // Keys and nonces of the EIP-8 test vectors, A is the initiator
+    const KEY_A: &str = "49a7b37aa6f6645917e7b807e9d1c00d4fa71f18343b0d4122a4d2df64dd6fee";
+    const KEY_B: &str = "b71c71a67e1177ad4e901695e1b4b9ee17ae16c6668d313eac2f96dbcda3f291";
+    const EPH_KEY_A: &str = "869d6ecf5211f1cc60418a13b9d870b22959d0c16f02bec714c960dd2298a32d";
+    const EPH_KEY_B: &str = "e238eb8e04fee6511ab04c6dd3c89ce097b11f25d584863ac2b6d5b35b1847e4";
+    const NONCE_A: &str = "7e968bba13b6c50e2c4cd7f241cc0d64d1ac25c7f5952df231ac6a2bda8ee5d6";
+    const NONCE_B: &str = "559aead08264d5795d3909718cdd05abd49572e84fe55590eef31a88a08fdffd";
+
+    // Secrets derived by both sides from the keys and nonces above
+    const AES_SECRET: &str = "80e8632c05fed6fc2a13b0f8d31a3cf645366239170ea067065aba8e28bac487";
+    const MAC_SECRET: &str = "2ea74ec5dae199227dff1af715362700e989d889d7a493cb0639691efb8e5f98";
+
+    /// auth1, legacy format
+    const AUTH1: &str = concat!(
+        "048ca79ad18e4b0659fab4853fe5bc58eb83992980f4c9cc147d2aa31532efd29a3d3dc6a3d89eaf",
+        "913150cfc777ce0ce4af2758bf4810235f6e6ceccfee1acc6b22c005e9e3a49d6448610a58e98744",
+        "ba3ac0399e82692d67c1f58849050b3024e21a52c9d3b01d871ff5f210817912773e610443a9ef14",
+        "2e91cdba0bd77b5fdf0769b05671fc35f83d83e4d3b0b000c6b2a1b1bba89e0fc51bf4e460df3105",
+        "c444f14be226458940d6061c296350937ffd5e3acaceeaaefd3c6f74be8e23e0f45163cc7ebd7622",
+        "0f0128410fd05250273156d548a414444ae2f7dea4dfca2d43c057adb701a715bf59f6fb66b2d1d2",
+        "0f2c703f851cbf5ac47396d9ca65b6260bd141ac4d53e2de585a73d1750780db4c9ee4cd4d225173",
+        "a4592ee77e2bd94d0be3691f3b406f9bba9b591fc63facc016bfa8",
+    );
+
+    /// auth2, EIP8 format with version 4 and no additional list elements
+    const AUTH2: &str = concat!(
+        "01b304ab7578555167be8154d5cc456f567d5ba302662433674222360f08d5f1534499d3678b513b",
+        "0fca474f3a514b18e75683032eb63fccb16c156dc6eb2c0b1593f0d84ac74f6e475f1b8d56116b84",
+        "9634a8c458705bf83a626ea0384d4d7341aae591fae42ce6bd5c850bfe0b999a694a49bbbaf3ef6c",
+        "da61110601d3b4c02ab6c30437257a6e0117792631a4b47c1d52fc0f8f89caadeb7d02770bf999cc",
+        "147d2df3b62e1ffb2c9d8c125a3984865356266bca11ce7d3a688663a51d82defaa8aad69da39ab6",
+        "d5470e81ec5f2a7a47fb865ff7cca21516f9299a07b1bc63ba56c7a1a892112841ca44b6e0034dee",
+        "70c9adabc15d76a54f443593fafdc3b27af8059703f88928e199cb122362a4b35f62386da7caad09",
+        "c001edaeb5f8a06d2b26fb6cb93c52a9fca51853b68193916982358fe1e5369e249875bb8d0d0ec3",
+        "6f917bc5e1eafd5896d46bd61ff23f1a863a8a8dcd54c7b109b771c8e61ec9c8908c733c0263440e",
+        "2aa067241aaa433f0bb053c7b31a838504b148f570c0ad62837129e547678c5190341e4f1693956c",
+        "3bf7678318e2d5b5340c9e488eefea198576344afbdf66db5f51204a6961a63ce072c8926c",
+    );
+
+    /// auth3, EIP8 format with version 56 and 3 additional list elements
+    const AUTH3: &str = concat!(
+        "01b8044c6c312173685d1edd268aa95e1d495474c6959bcdd10067ba4c9013df9e40ff45f5bfd6f7",
+        "2471f93a91b493f8e00abc4b80f682973de715d77ba3a005a242eb859f9a211d93a347fa64b597bf",
+        "280a6b88e26299cf263b01b8dfdb712278464fd1c25840b995e84d367d743f66c0e54a586725b7bb",
+        "f12acca27170ae3283c1073adda4b6d79f27656993aefccf16e0d0409fe07db2dc398a1b7e8ee93b",
+        "cd181485fd332f381d6a050fba4c7641a5112ac1b0b61168d20f01b479e19adf7fdbfa0905f63352",
+        "bfc7e23cf3357657455119d879c78d3cf8c8c06375f3f7d4861aa02a122467e069acaf513025ff19",
+        "6641f6d2810ce493f51bee9c966b15c5043505350392b57645385a18c78f14669cc4d960446c1757",
+        "1b7c5d725021babbcd786957f3d17089c084907bda22c2b2675b4378b114c601d858802a55345a15",
+        "116bc61da4193996187ed70d16730e9ae6b3bb8787ebcaea1871d850997ddc08b4f4ea668fbf3740",
+        "7ac044b55be0908ecb94d4ed172ece66fd31bfdadf2b97a8bc690163ee11f5b575a4b44e36e2bfb2",
+        "f0fce91676fd64c7773bac6a003f481fddd0bae0a1f31aa27504e2a533af4cef3b623f4791b2cca6",
+        "d490",
+    );
+
+    /// ack1, legacy format
+    const ACK1: &str = concat!(
+        "049f8abcfa9c0dc65b982e98af921bc0ba6e4243169348a236abe9df5f93aa69d99cadddaa387662",
+        "b0ff2c08e9006d5a11a278b1b3331e5aaabf0a32f01281b6f4ede0e09a2d5f585b26513cb794d963",
+        "5a57563921c04a9090b4f14ee42be1a5461049af4ea7a7f49bf4c97a352d39c8d02ee4acc416388c",
+        "1c66cec761d2bc1c72da6ba143477f049c9d2dde846c252c111b904f630ac98e51609b3b1f58168d",
+        "dca6505b7196532e5f85b259a20c45e1979491683fee108e9660edbf38f3add489ae73e3dda2c71b",
+        "d1497113d5c755e942d1",
+    );
+
+    /// ack2, EIP8 format with version 4 and no additional list elements
+    const ACK2: &str = concat!(
+        "01ea0451958701280a56482929d3b0757da8f7fbe5286784beead59d95089c217c9b917788989470",
+        "b0e330cc6e4fb383c0340ed85fab836ec9fb8a49672712aeabbdfd1e837c1ff4cace34311cd7f4de",
+        "05d59279e3524ab26ef753a0095637ac88f2b499b9914b5f64e143eae548a1066e14cd2f4bd7f814",
+        "c4652f11b254f8a2d0191e2f5546fae6055694aed14d906df79ad3b407d94692694e259191cde171",
+        "ad542fc588fa2b7333313d82a9f887332f1dfc36cea03f831cb9a23fea05b33deb999e85489e645f",
+        "6aab1872475d488d7bd6c7c120caf28dbfc5d6833888155ed69d34dbdc39c1f299be1057810f34fb",
+        "e754d021bfca14dc989753d61c413d261934e1a9c67ee060a25eefb54e81a4d14baff922180c395d",
+        "3f998d70f46f6b58306f969627ae364497e73fc27f6d17ae45a413d322cb8814276be6ddd13b885b",
+        "201b943213656cde498fa0e9ddc8e0b8f8a53824fbd82254f3e2c17e8eaea009c38b4aa0a3f306e8",
+        "797db43c25d68e86f262e564086f59a2fc60511c42abfb3057c247a8a8fe4fb3ccbadde17514b7ac",
+        "8000cdb6a912778426260c47f38919a91f25f4b5ffb455d6aaaf150f7e5529c100ce62d6d92826a7",
+        "1778d809bdf60232ae21ce8a437eca8223f45ac37f6487452ce626f549b3b5fdee26afd2072e4bc7",
+        "5833c2464c805246155289f4",
+    );
+
+    /// ack3, EIP8 format with version 57 and 3 additional list elements
+    const ACK3: &str = concat!(
+        "01f004076e58aae772bb101ab1a8e64e01ee96e64857ce82b1113817c6cdd52c09d26f7b90981cd7",
+        "ae835aeac72e1573b8a0225dd56d157a010846d888dac7464baf53f2ad4e3d584531fa203658fab0",
+        "3a06c9fd5e35737e417bc28c1cbf5e5dfc666de7090f69c3b29754725f84f75382891c561040ea1d",
+        "dc0d8f381ed1b9d0d4ad2a0ec021421d847820d6fa0ba66eaf58175f1b235e851c7e2124069fbc20",
+        "2888ddb3ac4d56bcbd1b9b7eab59e78f2e2d400905050f4a92dec1c4bdf797b3fc9b2f8e84a482f3",
+        "d800386186712dae00d5c386ec9387a5e9c9a1aca5a573ca91082c7d68421f388e79127a5177d4f8",
+        "590237364fd348c9611fa39f78dcdceee3f390f07991b7b47e1daa3ebcb6ccc9607811cb17ce51f1",
+        "c8c2c5098dbdd28fca547b3f58c01a424ac05f869f49c6a34672ea2cbbc558428aa1fe48bbfd6115",
+        "8b1b735a65d99f21e70dbc020bfdface9f724a0d1fb5895db971cc81aa7608baa0920abb0a565c9c",
+        "436e2fd13323428296c86385f2384e408a31e104670df0791d93e743a3a5194ee6b076fb6323ca59",
+        "3011b7348c16cf58f66b9633906ba54a2ee803187344b394f75dd2e663a57b956cb830dd7a908d4f",
+        "39a2336a61ef9fda549180d4ccde21514d117b6c6fd07a9102b5efe710a32af4eeacae2cb3b1dec0",
+        "35b9593b48b9d3ca4c13d245d5f04169b0b1",
+    );
+
+    fn bytes(value: &str) -> [u8; 32] {
+        hex::decode(value).unwrap().try_into().unwrap()
+    }
 
-        let mut header_data = {
-            // TODO not nice, will not fit if message is bigger
-            // Use BytesMut with resize
-            let size = [0, 0, mac_data.len() as u8];
-            let cap = CapHeader::builder().cap_id(0).context_id(0).build();
-
-            let mut res = BytesMut::new();
-            res.put(size.as_slice());
-            res.put(rlp::encode(&cap));
-            res.resize(16, 0);
-            res
+    fn pub_key_of(key: &str) -> [u8; 64] {
+        pub_key(&bytes(key)).unwrap()
+    }
+
+    /// Handshake with the fixed ephemeral key and nonce instead of random ones
+    fn rlpx<S>(key: &str, eph_key: &str, nonce: &str, client_id: &[u8], state: S) -> Rlpx<S> {
+        let client_id = Bytes::copy_from_slice(client_id);
+        let mut rlpx = Rlpx::new(&bytes(key), client_id, Arc::new(Native), state).unwrap();
+        rlpx.eph_private_key = Zeroizing::new(bytes(eph_key));
+        rlpx.nonce = Zeroizing::new(bytes(nonce));
+        rlpx
+    }
+
+    /// Body of the EIP8 message as decrypted by the recipient
+    fn decrypt<T: rlp::Decodable>(key: &str, msg: &[u8]) -> T {
+        let (size, enc) = split_size(msg).unwrap();
+        rlp::decode(&ecies::decrypt(&bytes(key), enc, size).unwrap()).unwrap()
+    }
+
+    fn keystream(aes: &mut Aes256Ctr) -> [u8; 32] {
+        let mut buf = [0; 32];
+        aes.apply_keystream(&mut buf);
+        buf
+    }
+
+    /// Keystreams are compared instead of the AES secret, MAC tags instead of
+    /// the MAC secret
+    fn check_secrets(
+        rlpx: Rlpx<Established>,
+        nonce: &str,
+        rem_nonce: &str,
+        sent: &[u8],
+        received: &[u8],
+    ) {
+        let mut state = rlpx.state;
+
+        let mut aes = Aes256Ctr::new_from_slices(&bytes(AES_SECRET), &[0; 16]).unwrap();
+        let expected = keystream(&mut aes);
+        assert_eq!(keystream(&mut state.egress_aes), expected);
+        assert_eq!(keystream(&mut state.ingress_aes), expected);
+
+        let mac = |nonce: &str, data: &[u8]| {
+            let mut mac = Mac::with_secret(&bytes(MAC_SECRET)).unwrap();
+            mac.update(&xor(&bytes(MAC_SECRET), &bytes(nonce)));
+            mac.update(data);
+            mac.header_tag(&[0; 16])
         };
+        assert_eq!(state.egress_mac.header_tag(&[0; 16]), mac(rem_nonce, sent));
+        assert_eq!(state.ingress_mac.header_tag(&[0; 16]), mac(nonce, received));
+    }
 
-        if let Some(aes) = self.aes.as_mut() {
-            aes.apply_keystream(&mut header_data);
-            aes.apply_keystream(&mut mac_data);
-        }
-        else {
-            panic!("Needs to be defined");
-        }
+    /// Recipient B decodes the auth of A, its ack leads to the EIP-8 secrets
+    async fn parse_auth(msg: &[u8], legacy: bool) {
+        let rlpx = rlpx(KEY_B, EPH_KEY_B, NONCE_B, &[], Accepted);
+        let rlpx = rlpx.parse_auth(msg).await.unwrap();
 
-        let (header_tag, mac_tag) = if let Some(mac) = self.mac.as_mut() {
-            (mac.header_tag(&header_data), mac.data_tag(&mac_data))
-        }
-        else {
-            panic!("Needs to be defined");
+        assert_eq!(rlpx.remote_id(), pub_key_of(KEY_A));
+        assert_eq!(rlpx.state.rem_eph_pub_key[..], pub_key_of(EPH_KEY_A));
+        assert_eq!(rlpx.state.rem_nonce[..], bytes(NONCE_A));
+        assert_eq!(rlpx.state.legacy, legacy);
+
+        let (rlpx, ack) = rlpx.get_ack().await.unwrap();
+        check_secrets(rlpx, NONCE_B, NONCE_A, &ack, msg);
+    }
+
+    /// Initiator A decodes the ack of B to its auth
+    async fn parse_ack(msg: &[u8], auth: &str, legacy: bool) {
+        let auth = Bytes::from(hex::decode(auth).unwrap());
+        let state = AuthSent {
+            auth: auth.clone(),
+            legacy,
         };
+        let rlpx = rlpx(KEY_A, EPH_KEY_A, NONCE_A, &pub_key_of(KEY_B), state);
 
-        BytesMut::from_iter(
-            header_data
-                .iter()
-                .chain(header_tag.iter())
-                .chain(mac_data.iter())
-                .chain(mac_tag.iter()),
-        )
-        .freeze()
+        let rlpx = rlpx.parse_ack(msg).await.unwrap();
+        check_secrets(rlpx, NONCE_A, NONCE_B, &auth, msg);
+    }
+
+    fn check_ack(ack: AckMsg, version: u16) {
+        assert_eq!(ack.pub_key[..], pub_key_of(EPH_KEY_B));
+        assert_eq!(ack.nonce[..], bytes(NONCE_B));
+        assert_eq!(ack.version, version);
+    }
+
+    #[tokio::test]
+    async fn auth1() {
+        parse_auth(&hex::decode(AUTH1).unwrap(), true).await;
+    }
+
+    #[tokio::test]
+    async fn auth2() {
+        let msg = hex::decode(AUTH2).unwrap();
+        assert_eq!(decrypt::<AuthMsg>(KEY_B, &msg).version, 4);
+        parse_auth(&msg, false).await;
+    }
+
+    #[tokio::test]
+    async fn auth3() {
+        let msg = hex::decode(AUTH3).unwrap();
+        assert_eq!(decrypt::<AuthMsg>(KEY_B, &msg).version, 56);
+        parse_auth(&msg, false).await;
+    }
+
+    #[tokio::test]
+    async fn ack1() {
+        let msg = hex::decode(ACK1).unwrap();
+        let body = ecies::decrypt(&bytes(KEY_A), &msg, &[]).unwrap();
+        assert_eq!(body[..64], pub_key_of(EPH_KEY_B));
+        assert_eq!(body[64..96], bytes(NONCE_B));
+        parse_ack(&msg, AUTH1, true).await;
+    }
+
+    #[tokio::test]
+    async fn ack2() {
+        let msg = hex::decode(ACK2).unwrap();
+        check_ack(decrypt(KEY_A, &msg), 4);
+        parse_ack(&msg, AUTH2, false).await;
+    }
+
+    #[tokio::test]
+    async fn ack3() {
+        let msg = hex::decode(ACK3).unwrap();
+        check_ack(decrypt(KEY_A, &msg), 57);
+        parse_ack(&msg, AUTH2, false).await;
+    }
+
+    /// Legacy auth is detected by decrypting it, EIP8 one by its size prefix
+    #[tokio::test]
+    async fn auth_size() {
+        let rlpx = Rlpx::recipient(&bytes(KEY_B), Arc::new(Native)).unwrap();
+
+        let auth1 = hex::decode(AUTH1).unwrap();
+        assert_eq!(rlpx.auth_size(&auth1).await.unwrap(), AUTH_LEGACY_SIZE);
+
+        let auth2 = hex::decode(AUTH2).unwrap();
+        assert_eq!(rlpx.auth_size(&auth2[..AUTH_LEGACY_SIZE]).await.unwrap(), auth2.len());
+    }
+
+    #[tokio::test]
+    async fn legacy_roundtrip() {
+        let initiator = Rlpx::with_private_key(&bytes(KEY_A), &pub_key_of(KEY_B), Arc::new(Native));
+        let (initiator, auth) = initiator.unwrap().get_auth_legacy().await.unwrap();
+        assert_eq!(auth.len(), AUTH_LEGACY_SIZE);
+
+        let recipient = Rlpx::recipient(&bytes(KEY_B), Arc::new(Native)).unwrap();
+        let recipient = recipient.parse_auth(&auth).await.unwrap();
+        assert!(recipient.state.legacy);
+        let (recipient, ack) = recipient.get_ack().await.unwrap();
+        assert_eq!(ack.len(), ACK_LEGACY_SIZE);
+
+        let initiator = initiator.parse_ack(&ack).await.unwrap();
+        let (mut initiator, mut recipient) = (initiator.state, recipient.state);
+        assert_eq!(keystream(&mut initiator.egress_aes), keystream(&mut recipient.ingress_aes));
+        assert_eq!(keystream(&mut initiator.ingress_aes), keystream(&mut recipient.egress_aes));
+        assert_eq!(
+            initiator.egress_mac.header_tag(&[0; 16]),
+            recipient.ingress_mac.header_tag(&[0; 16])
+        );
+        assert_eq!(
+            initiator.ingress_mac.header_tag(&[0; 16]),
+            recipient.egress_mac.header_tag(&[0; 16])
+        );
     }
 }
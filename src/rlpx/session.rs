@@ -0,0 +1,213 @@
+//! Base "p2p" protocol running on the established session
+//!
+//! Disconnect (0x01) = [reason: P]
+//! Ping (0x02) = []
+//! Pong (0x03) = []
+//!
+//! Pings are answered and sent periodically, messages of the capabilities
+//! are passed through in both directions
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use fehler::{throw, throws};
+use futures::{Sink, SinkExt, StreamExt};
+use rlp::Rlp;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{interval_at, Instant, Interval};
+use tokio_util::codec::Framed;
+
+use super::types::{DisconnectReason, Message, DISCONNECT_ID, PING_ID, PONG_ID};
+use super::RlpxCodec;
+use crate::error::{Disconnected, PingTimeout, Result};
+use crate::Error;
+
+/// Same as geth
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Empty RLP list
+const EMPTY_LIST: &[u8] = &[0xc0];
+
+pub struct Session<T> {
+    framed: Framed<T, RlpxCodec>,
+    ping: Interval,
+    pong_pending: bool,
+}
+
+impl<T> Session<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Must be created after the Hello messages are exchanged
+    pub fn new(framed: Framed<T, RlpxCodec>) -> Self {
+        Self {
+            framed,
+            ping: interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL),
+            pong_pending: false,
+        }
+    }
+
+    /// Next message of the capabilities, base protocol messages are handled
+    /// here and Disconnect of the peer is returned as an error
+    /// Cancel safe, can be used in `select!`
+    #[throws]
+    pub async fn next(&mut self) -> Option<Message> {
+        loop {
+            let msg = tokio::select! {
+                msg = self.framed.next() => msg.transpose()?,
+                _ = self.ping.tick() => {
+                    if self.pong_pending {
+                        self.disconnect(DisconnectReason::PingTimeout).await?;
+                        throw!(PingTimeout.build());
+                    }
+                    self.framed.send(ping()).await?;
+                    self.pong_pending = true;
+                    continue;
+                }
+            };
+
+            match msg {
+                Some(msg) if msg.id == DISCONNECT_ID => throw!(Disconnected {
+                    reason: disconnect_reason(&msg.data)?
+                }
+                .build()),
+                Some(msg) if msg.id == PING_ID => self.framed.send(pong()).await?,
+                Some(msg) if msg.id == PONG_ID => self.pong_pending = false,
+                msg => break msg,
+            }
+        }
+    }
+
+    /// Sends the Disconnect message and closes the connection
+    #[throws]
+    pub async fn disconnect(&mut self, reason: DisconnectReason) {
+        self.framed.send(disconnect(reason)).await?;
+        self.framed.close().await?;
+    }
+}
+
+/// Messages of the capabilities are sent as they are
+impl<T> Sink<Message> for Session<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.framed.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, msg: Message) -> Result<()> {
+        self.framed.start_send_unpin(msg)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.framed.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.framed.poll_close_unpin(cx)
+    }
+}
+
+pub fn disconnect(reason: DisconnectReason) -> Message {
+    let data = rlp::encode_list::<u8, u8>(&[reason.into()]).freeze();
+    Message::builder().id(DISCONNECT_ID).data(data).build()
+}
+
+pub fn ping() -> Message {
+    Message::builder().id(PING_ID).data(Bytes::from_static(EMPTY_LIST)).build()
+}
+
+pub fn pong() -> Message {
+    Message::builder().id(PONG_ID).data(Bytes::from_static(EMPTY_LIST)).build()
+}
+
+/// Reason is a single element list, some clients send it without the list
+#[throws]
+pub fn disconnect_reason(data: &[u8]) -> DisconnectReason {
+    let rlp = Rlp::new(data);
+    let code: u8 = match rlp.is_list() {
+        true if rlp.item_count()? == 0 => 0,
+        true => rlp.val_at(0)?,
+        false => rlp.as_val()?,
+    };
+    code.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+    use crate::rlpx::frame::tests::established;
+    use crate::rlpx::Limits;
+
+    /// Session and the raw messages of its peer, Hellos are not exchanged so
+    /// nothing is compressed
+    fn session() -> (Session<DuplexStream>, Framed<DuplexStream, RlpxCodec>) {
+        let (local, remote) = duplex(4096);
+        let framed = |stream| Framed::new(stream, RlpxCodec::new(established(), Limits::default()));
+        (Session::new(framed(local)), framed(remote))
+    }
+
+    fn message() -> Message {
+        Message::builder().id(0x10).data(Bytes::from_static(EMPTY_LIST)).build()
+    }
+
+    #[tokio::test]
+    async fn answers_ping() {
+        let (mut session, mut peer) = session();
+        peer.send(ping()).await.unwrap();
+        peer.send(message()).await.unwrap();
+
+        assert_eq!(session.next().await.unwrap().unwrap().id, 0x10);
+        assert_eq!(peer.next().await.unwrap().unwrap().id, PONG_ID);
+    }
+
+    #[tokio::test]
+    async fn sends_messages() {
+        let (mut session, mut peer) = session();
+        session.send(message()).await.unwrap();
+        assert_eq!(peer.next().await.unwrap().unwrap().id, 0x10);
+    }
+
+    #[tokio::test]
+    async fn peer_disconnects() {
+        let (mut session, mut peer) = session();
+        peer.send(disconnect(DisconnectReason::TooManyPeers)).await.unwrap();
+
+        let res = session.next().await;
+        assert!(matches!(
+            res,
+            Err(Error::Disconnected {
+                reason: DisconnectReason::TooManyPeers
+            })
+        ));
+    }
+
+    /// First ping is sent after one interval, missing pong is noticed after
+    /// the next one
+    #[tokio::test(start_paused = true)]
+    async fn ping_timeout() {
+        let (mut session, mut peer) = session();
+
+        let start = Instant::now();
+        assert!(matches!(session.next().await, Err(Error::PingTimeout)));
+        assert_eq!(start.elapsed(), 2 * PING_INTERVAL);
+
+        assert_eq!(peer.next().await.unwrap().unwrap().id, PING_ID);
+        let msg = peer.next().await.unwrap().unwrap();
+        assert_eq!(msg.id, DISCONNECT_ID);
+        assert_eq!(disconnect_reason(&msg.data).unwrap(), DisconnectReason::PingTimeout);
+    }
+
+    #[test]
+    fn disconnect_reasons() {
+        assert_eq!(disconnect_reason(&[0xc1, 0x04]).unwrap(), DisconnectReason::TooManyPeers);
+        assert_eq!(disconnect_reason(&[0x04]).unwrap(), DisconnectReason::TooManyPeers);
+        assert_eq!(disconnect_reason(&[0xc0]).unwrap(), DisconnectReason::Requested);
+    }
+}
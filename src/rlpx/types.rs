@@ -2,29 +2,90 @@
 //! This protocol is position based, which means that the order of member DOES
 //! mather
 
+use std::fmt;
+
 use bytes::Bytes;
-use rlp_derive::{RlpDecodable, RlpDecodableWrapper, RlpEncodable, RlpEncodableWrapper};
+use rlp::{Decodable, DecoderError, Encodable, Rlp, RlpStream};
+use rlp_derive::{RlpDecodable, RlpEncodable};
 use typed_builder::TypedBuilder;
 
-// (currently unused in spec)
-#[derive(RlpEncodable, RlpDecodable, TypedBuilder)]
+use crate::crypto::ecies;
+
+/// Legacy pre-EIP8 handshake messages have a fixed size
+/// sig || keccak256(ephemeral-pubk) || pubk || nonce || 0x0
+pub const AUTH_LEGACY_BODY_SIZE: usize = 65 + 32 + 64 + 32 + 1;
+pub const AUTH_LEGACY_SIZE: usize = AUTH_LEGACY_BODY_SIZE + ecies::OVERHEAD;
+/// ephemeral-pubk || nonce || 0x0
+pub const ACK_LEGACY_BODY_SIZE: usize = 64 + 32 + 1;
+pub const ACK_LEGACY_SIZE: usize = ACK_LEGACY_BODY_SIZE + ecies::OVERHEAD;
+
+/// header-data = [capability-id, context-id, total-packet-size]
+/// Both IDs are unused by the current spec and always zero, total-packet-size
+/// is only sent by legacy peers in the first frame of a chunked packet
+/// Missing context-id is zero, additional list elements are ignored
+#[derive(TypedBuilder)]
 pub struct CapHeader {
-    cap_id: u16,
-    context_id: u16,
+    pub cap_id: u16,
+    pub context_id: u16,
+    #[builder(default)]
+    pub total_size: Option<u32>,
+}
+
+impl Encodable for CapHeader {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(2 + self.total_size.is_some() as usize);
+        s.append(&self.cap_id);
+        s.append(&self.context_id);
+        if let Some(total_size) = &self.total_size {
+            s.append(total_size);
+        }
+    }
+}
+
+impl Decodable for CapHeader {
+    fn decode(rlp: &Rlp) -> Result<Self, DecoderError> {
+        let (context_id, total_size) = match rlp.item_count()? {
+            0 => return Err(DecoderError::RlpIncorrectListLen),
+            1 => (0, None),
+            2 => (rlp.val_at(1)?, None),
+            _ => (rlp.val_at(1)?, Some(rlp.val_at(2)?)),
+        };
+
+        Ok(Self {
+            cap_id: rlp.val_at(0)?,
+            context_id,
+            total_size,
+        })
+    }
 }
 
+/// Handshake messages and the Hello are decoded leniently as EIP8 requires,
+/// additional list elements are ignored and higher versions are accepted
 #[derive(RlpEncodable, RlpDecodable, TypedBuilder)]
 pub struct AuthMsg {
-    sig: Bytes,
-    pub_key: Bytes,
-    nonce: Bytes,
-    version: u16,
+    pub sig: Bytes,
+    pub pub_key: Bytes,
+    pub nonce: Bytes,
+    pub version: u16,
 }
 
 #[derive(RlpEncodable, RlpDecodable, TypedBuilder)]
+pub struct AckMsg {
+    pub pub_key: Bytes,
+    pub nonce: Bytes,
+    pub version: u16,
+}
+
+#[derive(RlpEncodable, RlpDecodable, TypedBuilder, Debug, Clone)]
 pub struct Protocol {
-    name: String,
-    t: u32,
+    pub name: String,
+    pub version: u32,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.name, self.version)
+    }
 }
 
 #[derive(RlpEncodable, RlpDecodable, TypedBuilder)]
@@ -36,6 +97,29 @@ pub struct HelloMsg {
     pub_key: Bytes,
 }
 
+/// Remote peer as described by its Hello
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub version: u32,
+    pub client_id: String,
+    pub capabilities: Vec<Protocol>,
+    pub port: u16,
+    pub node_id: Bytes,
+}
+
+/// Decoding ignores any additional list elements of the Hello
+impl From<HelloMsg> for PeerInfo {
+    fn from(hello: HelloMsg) -> Self {
+        Self {
+            version: hello.version,
+            client_id: hello.name,
+            capabilities: hello.protocols,
+            port: hello.port,
+            node_id: hello.pub_key,
+        }
+    }
+}
+
 #[derive(RlpEncodable, RlpDecodable, TypedBuilder)]
 pub struct Endpoint {
     address: String,
@@ -51,8 +135,128 @@ pub struct Ping {
     timestamp: u64,
 }
 
-// Wrappers
-#[derive(RlpEncodableWrapper, RlpDecodableWrapper, TypedBuilder)]
-pub struct HelloPrefixMsg {
-    zero: u32,
+/// Message IDs of the base "p2p" protocol
+pub const HELLO_ID: u64 = 0x00;
+pub const DISCONNECT_ID: u64 = 0x01;
+pub const PING_ID: u64 = 0x02;
+pub const PONG_ID: u64 = 0x03;
+
+/// Version of the base protocol we advertise, 5 enables snappy compression
+pub const PROTOCOL_VERSION: u32 = 5;
+
+/// Decrypted frame of the established session
+#[derive(Debug, Clone, TypedBuilder)]
+pub struct Message {
+    pub id: u64,
+    pub data: Bytes,
+}
+
+/// Reason sent in the Disconnect message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    Requested,
+    TcpError,
+    ProtocolBreach,
+    UselessPeer,
+    TooManyPeers,
+    AlreadyConnected,
+    IncompatibleVersion,
+    NullIdentity,
+    ClientQuitting,
+    UnexpectedIdentity,
+    SameIdentity,
+    PingTimeout,
+    SubprotocolError,
+    Unknown(u8),
+}
+
+impl From<u8> for DisconnectReason {
+    fn from(code: u8) -> Self {
+        match code {
+            0x00 => Self::Requested,
+            0x01 => Self::TcpError,
+            0x02 => Self::ProtocolBreach,
+            0x03 => Self::UselessPeer,
+            0x04 => Self::TooManyPeers,
+            0x05 => Self::AlreadyConnected,
+            0x06 => Self::IncompatibleVersion,
+            0x07 => Self::NullIdentity,
+            0x08 => Self::ClientQuitting,
+            0x09 => Self::UnexpectedIdentity,
+            0x0a => Self::SameIdentity,
+            0x0b => Self::PingTimeout,
+            0x10 => Self::SubprotocolError,
+            code => Self::Unknown(code),
+        }
+    }
+}
+
+impl From<DisconnectReason> for u8 {
+    fn from(reason: DisconnectReason) -> Self {
+        match reason {
+            DisconnectReason::Requested => 0x00,
+            DisconnectReason::TcpError => 0x01,
+            DisconnectReason::ProtocolBreach => 0x02,
+            DisconnectReason::UselessPeer => 0x03,
+            DisconnectReason::TooManyPeers => 0x04,
+            DisconnectReason::AlreadyConnected => 0x05,
+            DisconnectReason::IncompatibleVersion => 0x06,
+            DisconnectReason::NullIdentity => 0x07,
+            DisconnectReason::ClientQuitting => 0x08,
+            DisconnectReason::UnexpectedIdentity => 0x09,
+            DisconnectReason::SameIdentity => 0x0a,
+            DisconnectReason::PingTimeout => 0x0b,
+            DisconnectReason::SubprotocolError => 0x10,
+            DisconnectReason::Unknown(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Self::Requested => "disconnect requested",
+            Self::TcpError => "TCP sub-system error",
+            Self::ProtocolBreach => "breach of protocol",
+            Self::UselessPeer => "useless peer",
+            Self::TooManyPeers => "too many peers",
+            Self::AlreadyConnected => "already connected",
+            Self::IncompatibleVersion => "incompatible P2P protocol version",
+            Self::NullIdentity => "null node identity received",
+            Self::ClientQuitting => "client quitting",
+            Self::UnexpectedIdentity => "unexpected identity in handshake",
+            Self::SameIdentity => "identity is the same as this node",
+            Self::PingTimeout => "ping timeout",
+            Self::SubprotocolError => "subprotocol specific reason",
+            Self::Unknown(code) => return write!(f, "unknown reason {code:#04x}"),
+        };
+        f.write_str(reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hello of a future version with additional list elements
+    #[test]
+    fn hello_additional_elements() {
+        let mut s = RlpStream::new_list(8);
+        s.append(&22_u32);
+        s.append(&"Geth/v9");
+        s.begin_list(2);
+        s.begin_list(2).append(&"eth").append(&68_u32);
+        s.begin_list(2).append(&"snap").append(&1_u32);
+        s.append(&30303_u16);
+        s.append(&[0x04_u8; 64].as_slice());
+        s.append(&"additional");
+        s.begin_list(2).append(&1_u8).append(&2_u8);
+        s.append_empty_data();
+
+        let peer = PeerInfo::from(rlp::decode::<HelloMsg>(&s.out()).unwrap());
+        assert_eq!((peer.version, peer.client_id.as_str(), peer.port), (22, "Geth/v9", 30303));
+        let caps = peer.capabilities.iter().map(Protocol::to_string).collect::<Vec<_>>();
+        assert_eq!(caps, ["eth/68", "snap/1"]);
+        assert_eq!(peer.node_id[..], [0x04; 64]);
+    }
 }
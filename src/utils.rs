@@ -5,7 +5,6 @@ use core::slice::SlicePattern;
 use bytes::{Bytes, BytesMut};
 use fehler::throws;
 use rand::{thread_rng, RngCore};
-use tokio::process::Command;
 
 use crate::Error;
 
@@ -27,26 +26,14 @@ pub fn id2pk(id: &[u8]) -> Bytes {
     BytesMut::from_iter([4].iter().chain(id.iter())).freeze()
 }
 
-pub fn nonce() -> Bytes {
+pub fn nonce() -> [u8; 32] {
     let mut nonce = [0; 32];
     let mut rng = thread_rng();
     rng.fill_bytes(&mut nonce);
-    Bytes::copy_from_slice(nonce.as_slice()) // TODO
+    nonce
 }
 
 // TODO
 pub fn align_16(a: usize) -> usize {
     ((a as f64 / 16.0).ceil() * 16.0) as usize
 }
-
-#[throws]
-pub async fn node(args: String) -> String {
-    let output = Command::new("node")
-        .arg("./ffi.js")
-        .arg(args)
-        .current_dir("./auth")
-        .output()
-        .await?;
-
-    String::from_utf8(output.stdout[..output.stdout.len() - 1].to_vec())?
-}